@@ -0,0 +1,562 @@
+// Slash commands and the `names: message` shorthand.
+//
+// Every command is one entry in `COMMANDS`: its name, a usage line, a help text
+// and a function that checks the arguments and turns them into a `Command`.
+// Adding a command means adding an entry and a `Command` variant, the network
+// loop only ever sees parsed commands.
+
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    //without text, only switches the conversation
+    Msg {
+        to: Vec<String>,
+        text: Option<String>,
+    },
+    Send {
+        to: Vec<String>,
+        path: String,
+    },
+    Create {
+        room: String,
+        topic: Option<String>,
+    },
+    Join {
+        room: String,
+    },
+    //None leaves the current conversation
+    Leave {
+        room: Option<String>,
+    },
+    Rooms,
+    //None lists everyone online
+    Who {
+        room: Option<String>,
+    },
+    Block {
+        user: String,
+    },
+    Unblock {
+        user: String,
+    },
+    Offers,
+    Accept {
+        offer: String,
+    },
+    Reject {
+        offer: String,
+    },
+    Sessions,
+    Revoke {
+        session: String,
+    },
+    Unlock {
+        target: String,
+    },
+    Stats,
+    Help {
+        command: Option<String>,
+    },
+    Quit,
+}
+
+//What a line of input turned out to be
+#[derive(Debug, PartialEq)]
+pub enum Parsed {
+    Command(Command),
+    Text(String), //for the current conversation
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Unknown(String),
+    Usage {
+        spec: &'static Spec,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => {
+                write!(f, "There is no command /{}, /help lists them", name)
+            }
+            CommandError::Usage { spec, reason } => {
+                write!(f, "{}, usage: {}", reason, spec.usage())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Spec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static str,
+    pub help: &'static str,
+    parse: fn(&str) -> Result<Command, &'static str>,
+}
+
+//Commands are told apart by name, comparing the parse functions means nothing
+impl PartialEq for Spec {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Spec {
+    pub fn usage(&self) -> String {
+        if self.args.is_empty() {
+            format!("/{}", self.name)
+        } else {
+            format!("/{} {}", self.name, self.args)
+        }
+    }
+}
+
+pub const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "msg",
+        aliases: &["m"],
+        args: "names [message]",
+        help: "Messages users or rooms, without a message switches the conversation to them",
+        parse: |args| {
+            let (names, text) = split_word(args);
+            Ok(Command::Msg {
+                to: names_list(names)?,
+                text: non_empty(text),
+            })
+        },
+    },
+    Spec {
+        name: "send",
+        aliases: &[],
+        args: "names path",
+        help: "Offers a file to users",
+        parse: |args| {
+            let (names, path) = split_word(args);
+            let path = non_empty(path).ok_or("Which file?")?;
+            Ok(Command::Send {
+                to: names_list(names)?,
+                path,
+            })
+        },
+    },
+    Spec {
+        name: "create",
+        aliases: &[],
+        args: "#room [topic]",
+        help: "Creates a room and joins it",
+        parse: |args| {
+            let (room, topic) = split_word(args);
+            Ok(Command::Create {
+                room: word(room, "Which room?")?,
+                topic: non_empty(topic),
+            })
+        },
+    },
+    Spec {
+        name: "join",
+        aliases: &[],
+        args: "#room",
+        help: "Joins a room and talks in it",
+        parse: |args| {
+            Ok(Command::Join {
+                room: only_word(args, "Which room?")?,
+            })
+        },
+    },
+    Spec {
+        name: "leave",
+        aliases: &["part"],
+        args: "[#room]",
+        help: "Leaves a room, the current one without a name",
+        parse: |args| {
+            Ok(Command::Leave {
+                room: optional_word(args)?,
+            })
+        },
+    },
+    Spec {
+        name: "rooms",
+        aliases: &[],
+        args: "",
+        help: "Lists every room",
+        parse: |args| no_args(args, Command::Rooms),
+    },
+    Spec {
+        name: "who",
+        aliases: &["members"],
+        args: "[#room]",
+        help: "Lists who is online, or the members of a room",
+        parse: |args| {
+            Ok(Command::Who {
+                room: optional_word(args)?,
+            })
+        },
+    },
+    Spec {
+        name: "block",
+        aliases: &[],
+        args: "user",
+        help: "Stops messages from a user",
+        parse: |args| {
+            Ok(Command::Block {
+                user: only_word(args, "Which user?")?,
+            })
+        },
+    },
+    Spec {
+        name: "unblock",
+        aliases: &[],
+        args: "user",
+        help: "Lets a blocked user message you again",
+        parse: |args| {
+            Ok(Command::Unblock {
+                user: only_word(args, "Which user?")?,
+            })
+        },
+    },
+    Spec {
+        name: "offers",
+        aliases: &[],
+        args: "",
+        help: "Lists the files offered to you",
+        parse: |args| no_args(args, Command::Offers),
+    },
+    Spec {
+        name: "accept",
+        aliases: &[],
+        args: "offer",
+        help: "Accepts a file offer by its number",
+        parse: |args| {
+            Ok(Command::Accept {
+                offer: only_word(args, "Which offer?")?,
+            })
+        },
+    },
+    Spec {
+        name: "reject",
+        aliases: &[],
+        args: "offer",
+        help: "Turns a file offer down",
+        parse: |args| {
+            Ok(Command::Reject {
+                offer: only_word(args, "Which offer?")?,
+            })
+        },
+    },
+    Spec {
+        name: "sessions",
+        aliases: &[],
+        args: "",
+        help: "Lists where you are logged in",
+        parse: |args| no_args(args, Command::Sessions),
+    },
+    Spec {
+        name: "revoke",
+        aliases: &[],
+        args: "session",
+        help: "Logs out a session by its id, it cannot log in again",
+        parse: |args| {
+            Ok(Command::Revoke {
+                session: only_word(args, "Which session?")?,
+            })
+        },
+    },
+    Spec {
+        name: "unlock",
+        aliases: &[],
+        args: "user|address",
+        help: "Lets a user or address locked out after failed logins try again, admins only",
+        parse: |args| {
+            Ok(Command::Unlock {
+                target: only_word(args, "Which user or address?")?,
+            })
+        },
+    },
+    Spec {
+        name: "stats",
+        aliases: &[],
+        args: "",
+        help: "Shows how far behind every connection is, admins only",
+        parse: |args| no_args(args, Command::Stats),
+    },
+    Spec {
+        name: "help",
+        aliases: &["?"],
+        args: "[command]",
+        help: "Lists the commands or explains one",
+        parse: |args| {
+            Ok(Command::Help {
+                command: optional_word(args)?.map(|name| name.trim_start_matches('/').to_string()),
+            })
+        },
+    },
+    Spec {
+        name: "quit",
+        aliases: &["exit"],
+        args: "",
+        help: "Leaves the chat",
+        parse: |args| no_args(args, Command::Quit),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Spec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name))
+}
+
+//Parses a line typed after login. `//text` sends text starting with a slash.
+//Inside a conversation every other line is text for it, `names: message` only
+//works without one.
+pub fn parse(line: &str, in_conversation: bool) -> Result<Parsed, CommandError> {
+    if let Some(text) = line.strip_prefix("//") {
+        return Ok(Parsed::Text(format!("/{}", text)));
+    }
+    if let Some(command) = line.strip_prefix('/') {
+        let (name, args) = split_word(command);
+        let spec = find(name).ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+        return (spec.parse)(args)
+            .map(Parsed::Command)
+            .map_err(|reason| CommandError::Usage { spec, reason });
+    }
+    Ok(addressed(line)
+        .filter(|_| !in_conversation)
+        .unwrap_or_else(|| Parsed::Text(line.trim().to_string())))
+}
+
+//`names: message` and `names: file: path`, the names being a single word before the colon
+fn addressed(line: &str) -> Option<Parsed> {
+    let (names, rest) = line.split_once(':')?;
+    let to = names_list(names.trim()).ok()?;
+    let rest = rest.trim();
+    let command = match rest.strip_prefix("file:") {
+        Some(path) if !path.trim().is_empty() => Command::Send {
+            to,
+            path: path.trim().to_string(),
+        },
+        _ => Command::Msg {
+            to,
+            text: non_empty(rest),
+        },
+    };
+    Some(Parsed::Command(command))
+}
+
+//The first word and the rest, both trimmed
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    Some(text.trim().to_string()).filter(|text| !text.is_empty())
+}
+
+fn word(text: &str, missing: &'static str) -> Result<String, &'static str> {
+    non_empty(text).ok_or(missing)
+}
+
+fn only_word(args: &str, missing: &'static str) -> Result<String, &'static str> {
+    match split_word(args) {
+        (_, rest) if !rest.is_empty() => Err("Too many arguments"),
+        (first, _) => word(first, missing),
+    }
+}
+
+fn optional_word(args: &str) -> Result<Option<String>, &'static str> {
+    match split_word(args) {
+        (_, rest) if !rest.is_empty() => Err("Too many arguments"),
+        (first, _) => Ok(non_empty(first)),
+    }
+}
+
+fn no_args(args: &str, command: Command) -> Result<Command, &'static str> {
+    if args.trim().is_empty() {
+        Ok(command)
+    } else {
+        Err("No arguments expected")
+    }
+}
+
+//Comma separated user or room names, `bob,carol` or `#rust`
+fn names_list(names: &str) -> Result<Vec<String>, &'static str> {
+    if names.is_empty() {
+        return Err("Who to?");
+    }
+    names
+        .split(',')
+        .map(|name| {
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                Err("Names are separated by commas, without spaces in them")
+            } else {
+                Ok(name.to_string())
+            }
+        })
+        .collect()
+}
+
+//One line per command for /help
+pub fn help(command: Option<&str>) -> Result<Vec<String>, CommandError> {
+    let specs: Vec<&Spec> = match command {
+        Some(name) => vec![find(name).ok_or_else(|| CommandError::Unknown(name.to_string()))?],
+        None => COMMANDS.iter().collect(),
+    };
+    let mut lines: Vec<String> = specs
+        .iter()
+        .map(|spec| format!("{:<24} {}", spec.usage(), spec.help))
+        .collect();
+    if command.is_none() {
+        lines.push(
+            "names: message is short for /msg names message outside a conversation".to_string(),
+        );
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Parsed, CommandError> {
+        parse(line, false)
+    }
+
+    fn command(line: &str) -> Command {
+        match parse_line(line) {
+            Ok(Parsed::Command(command)) => command,
+            other => panic!("{} parsed as {:?}", line, other),
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_commands_and_aliases() {
+        assert_eq!(
+            command("/msg bob,#rust  hello  there "),
+            Command::Msg {
+                to: names(&["bob", "#rust"]),
+                text: Some("hello  there".to_string())
+            }
+        );
+        assert_eq!(
+            command("/m bob"),
+            Command::Msg {
+                to: names(&["bob"]),
+                text: None
+            }
+        );
+        assert_eq!(
+            command("/send bob ~/My Files/a.txt"),
+            Command::Send {
+                to: names(&["bob"]),
+                path: "~/My Files/a.txt".to_string()
+            }
+        );
+        assert_eq!(
+            command("/create #rust all things rust"),
+            Command::Create {
+                room: "#rust".to_string(),
+                topic: Some("all things rust".to_string())
+            }
+        );
+        assert_eq!(command("/leave"), Command::Leave { room: None });
+        assert_eq!(
+            command("/members #rust"),
+            Command::Who {
+                room: Some("#rust".to_string())
+            }
+        );
+        assert_eq!(
+            command("/help /send"),
+            Command::Help {
+                command: Some("send".to_string())
+            }
+        );
+        assert_eq!(command("/exit"), Command::Quit);
+    }
+
+    #[test]
+    fn reports_usage_errors() {
+        let error = parse_line("/join").unwrap_err();
+        assert_eq!(error.to_string(), "Which room?, usage: /join #room");
+        assert!(matches!(
+            parse_line("/join #a #b"),
+            Err(CommandError::Usage {
+                reason: "Too many arguments",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_line("/send bob"),
+            Err(CommandError::Usage {
+                reason: "Which file?",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_line("/quit now"),
+            Err(CommandError::Usage { .. })
+        ));
+        assert_eq!(
+            parse_line("/dance"),
+            Err(CommandError::Unknown("dance".to_string()))
+        );
+        assert!(help(Some("dance")).is_err());
+        assert_eq!(help(None).unwrap().len(), COMMANDS.len() + 1);
+    }
+
+    #[test]
+    fn plain_lines() {
+        assert_eq!(
+            command("bob, carol: hi: there"),
+            Command::Msg {
+                to: names(&["bob", "carol"]),
+                text: Some("hi: there".to_string())
+            }
+        );
+        assert_eq!(
+            command("bob:file: notes.txt"),
+            Command::Send {
+                to: names(&["bob"]),
+                path: "notes.txt".to_string()
+            }
+        );
+        //a colon later in a sentence is just text
+        assert_eq!(
+            parse_line("see you at 10:30").unwrap(),
+            Parsed::Text("see you at 10:30".to_string())
+        );
+        assert_eq!(
+            parse_line("//shrug").unwrap(),
+            Parsed::Text("/shrug".to_string())
+        );
+    }
+
+    #[test]
+    fn shorthand_only_outside_a_conversation() {
+        //inside one the line goes to the conversation, not to a user called note
+        assert_eq!(
+            parse("note: call at 5", true).unwrap(),
+            Parsed::Text("note: call at 5".to_string())
+        );
+        assert_eq!(
+            parse("/msg note call at 5", true).unwrap(),
+            Parsed::Command(Command::Msg {
+                to: names(&["note"]),
+                text: Some("call at 5".to_string())
+            })
+        );
+    }
+}
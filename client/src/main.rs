@@ -1,194 +1,610 @@
-// imports necessary external crates and modules 
-//for serializing and deserializing Rust data structures to/from JSON.
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
-extern crate serde_json;
-
-use std::sync::Arc;
-
 use async_std::{
-    fs::File,
-    io::{stdin, BufReader},
-    net::{TcpStream, ToSocketAddrs},
+    io::BufReader,
+    net::TcpStream,
     prelude::*,
     task,
 };
-use futures::{select, FutureExt};
+use chat_common::protocol::{self, ClientRequest, DeliveryStatus, ErrorKind, HistoryMessage, LoginFailure, ServerEvent, SystemNotice};
+use futures::{channel::mpsc::UnboundedReceiver, select, FutureExt};
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod commands;
+mod reconnect;
+mod transfer;
+mod ui;
+
+use commands::{Command, Parsed};
+use reconnect::Backoff;
+use transfer::Transfers;
+use ui::{Action, Input, Output, Ui};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-//defineing structures for messages and file transfers:
-#[derive(Serialize, Deserialize, Debug)]
-struct FileTransfer {
-    destination: String,
-    filename: String,
-    data: Vec<u8>,
-}//Represents a file transfer with a destination, filename, and data
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type")]
-enum Message {
-    Text {
-        destination: String,
-        content: String,
-    },
-    File {
-        transfer: FileTransfer,
-    },
-    System {
-        info: String,
-    },
-}// An enum that can be either a text message, a file transfer, or a system message.
+const USAGE: &str = "Usage: client [--downloads DIR] [--max-file-size BYTES] [--state-dir DIR]\n";
+const SEEN: usize = 200; //room messages remembered so catching up does not show them twice
+const CATCH_UP_MARGIN: u64 = 10; //seconds of history asked for from before the disconnect, covers clock skew and whatever died with the connection
+
+struct Options {
+    downloads: PathBuf, //where accepted files are saved
+    max_file_size: u64, //bigger offers are rejected without asking
+    state_dir: PathBuf, //unfinished transfers of every server and account
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+    let mut options = Options { downloads: PathBuf::from("downloads"), max_file_size: 100 * 1024 * 1024, state_dir: transfer::default_state_dir() };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+        match flag.as_str() {
+            "--downloads" => options.downloads = PathBuf::from(value),
+            "--state-dir" => options.state_dir = PathBuf::from(value),
+            "--max-file-size" => options.max_file_size = value.parse().map_err(|_| format!("--max-file-size: {} is not a number of bytes", value))?,
+            _ => return Err(format!("unknown option {}\n{}", flag, USAGE).into()),
+        }
+    }
+    Ok(options)
+}
 
 // main
 fn main() -> Result<()> {
-    task::block_on(try_run("127.0.0.1:8080"))
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let options = parse_args(&args)?;
+    task::block_on(try_run("127.0.0.1:8080", options))
+}
+
+//How a connection ended
+enum Ended {
+    Quit,
+    Closed,
+    Lost(String),
+    Incompatible(String), //trying again will not help
 }
 
-async fn try_run(addr: impl ToSocketAddrs) -> Result<()> {
-    let stream = TcpStream::connect(addr).await?;
+//Where the login stands. The questions are asked here, the server only gets
+//the answers in one `Login` or `Register`.
+enum Login {
+    Hello,    //waiting for the server's hello
+    Resuming, //sent the session token
+    Account,  //asked whether the user has an account
+    Name { register: bool },
+    Password { register: bool, name: String },
+    Sent { register: bool, name: String },
+    Done,
+}
+
+//Rooms to get back into after a reconnect, and since when messages were missed
+struct CatchUp {
+    rooms: Vec<String>,
+    since: u64,
+}
 
-    let (reader, mut writer) = (&stream, &stream);
-    let mut lines_from_server = BufReader::new(reader).lines().fuse();
-    let mut lines_from_stdin = BufReader::new(stdin()).lines().fuse();
+//Everything that outlives a single connection
+struct Client {
+    ui: Ui,
+    out: Output,
+    output: UnboundedReceiver<String>,
+    transfers: Transfers,
+    me: Option<String>, //set by the welcome
+    quiet_rooms: bool, //the room list asked for after login only fills the side list
+    token: Option<String>, //session token of the last login, logs in again after a reconnect
+    login: Login,
+    catch_up: Option<CatchUp>,
+    seen: VecDeque<(String, String, String)>, //room, sender and text of recent room messages
+}
+
+async fn try_run(addr: &str, options: Options) -> Result<()> {
+    let (out, output) = Output::channel();
+    let ui = Ui::start()?;
+    let mut input = ui::input(ui.is_full_screen()).fuse();
+    let server_dir = options.state_dir.join(transfer::dir_name(addr));
+    let transfers = Transfers::new(options.downloads, server_dir, options.max_file_size, out.clone());
+    let mut client = Client {
+        ui, out, output, transfers,
+        me: None, quiet_rooms: false,
+        token: None, login: Login::Hello,
+        catch_up: None, seen: VecDeque::new(),
+    };
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
 
     loop {
-        select! {
-            line = lines_from_server.next().fuse() => match line {//From server: Parses incoming lines, determines message type, and processes them
-                Some(line) => {
-                    let line = line?;
-                    //println!("Received: {}", line);
-                    // Check for SYS: prefix
-                    let (dest, msg_block) = match line.find(':') { //splits message between destionation and message
-                        None => {
-                                    println!("{}",line);
-
-                                    continue},
-                        Some(idx) => (&line[..idx], line[idx + 1 ..].trim()),
-                    };
-                    let (msg_type, msg) = match msg_block.find(':') {
-                        None => {println!("{}",msg_block);continue},
-                        Some(idx) => (&msg_block[..idx], msg_block[idx + 1 ..].trim()),
+        client.ui.connection = match backoff.attempt() {
+            0 => format!("connecting to {}", addr),
+            attempt => format!("connecting to {} (attempt {})", addr, attempt + 1),
+        };
+        client.draw()?;
+        match TcpStream::connect(addr).await {
+            Ok(stream) => {
+                client.ui.connection = format!("connected to {}", addr);
+                match client.connection(&stream, &mut input).await? {
+                    Ended::Quit => return Ok(()),
+                    Ended::Closed => client.out.line("The server closed the connection"),
+                    Ended::Lost(e) => client.out.line(format!("Lost the connection: {}", e)),
+                    Ended::Incompatible(e) => return Err(e.into()),
+                }
+                //only a connection that got as far as a login starts the delays over
+                if client.me.is_some() {
+                    backoff.reset();
+                }
+                client.disconnected();
+            }
+            Err(e) => client.out.line(format!("Cannot connect to {}: {}", addr, e)),
+        }
+        let delay = backoff.next_delay();
+        client.out.line(format!("Trying again in {}s", delay.as_secs_f64().ceil()));
+        if client.wait(delay, backoff.attempt(), &mut input).await? {
+            return Ok(());
+        }
+    }
+}
+
+impl Client {
+    //Runs one connection until it ends, errors are the terminal's, the connection's only end it
+    async fn connection(&mut self, stream: &TcpStream, input: &mut (impl Stream<Item = io::Result<Input>> + Unpin)) -> Result<Ended> {
+        let mut lines_from_server = BufReader::new(stream).lines().fuse();
+        self.login = Login::Hello;
+        if let Err(e) = send_request(&ClientRequest::Hello { version: protocol::PROTOCOL_VERSION }, stream).await {
+            return Ok(Ended::Lost(e.to_string()));
+        }
+
+        let ended = loop {
+            select! {
+                line = lines_from_server.next().fuse() => match line {//From server: decodes incoming events and shows them
+                    Some(Ok(line)) => match self.event(&line, stream).await {
+                        Ok(Some(ended)) => break ended,
+                        Ok(None) => (),
+                        Err(e) => break Ended::Lost(e.to_string()),
+                    },
+                    Some(Err(e)) => break Ended::Lost(e.to_string()),
+                    None => break Ended::Closed,
+                },
+                event = input.next().fuse() => {//From the keyboard: edits the input line, submitted lines are sent
+                    let line = match event {
+                        Some(Ok(Input::Key(event))) => match self.ui.handle(event) {
+                            Action::Submit(line) => Some(line),
+                            Action::Quit => break Ended::Quit,
+                            Action::None => None,
+                        },
+                        Some(Ok(Input::Line(line))) => Some(line),
+                        Some(Err(e)) => return Err(e.into()),
+                        None => break Ended::Quit,
                     };
-                    if msg_type.eq("file"){
-                        // save_to_file(msg).await?
-                        println!("IT DON' WORK :)")
+                    if let Some(line) = line {
+                        match self.submit(line, stream).await {
+                            Ok(true) => break Ended::Quit,
+                            Ok(false) => (),
+                            Err(e) => break Ended::Lost(e.to_string()),
+                        }
                     }
-                    if msg_type.eq("text"){
+                }
+            }
+            self.draw()?;
+        };
+        Ok(ended)
+    }
 
-                        println!("From {}: {}",dest, msg);
+    //Handles one frame from the server, Some when it ends the connection
+    async fn event(&mut self, line: &str, stream: &TcpStream) -> Result<Option<Ended>> {
+        let out = &self.out;
+        let transfers = &mut self.transfers;
+        match protocol::decode::<ServerEvent>(line) {
+            Ok(ServerEvent::Message { from, content }) => out.line(format!("From {}: {}", from, content)),
+            Ok(ServerEvent::RoomMessage { room, from, content }) => {
+                out.line(format!("[{}] {}: {}", room, from, content));
+                self.saw(room, from, content);
+            }
+            Ok(ServerEvent::RoomJoined { room, user }) => {
+                if self.me.as_deref() == Some(user.as_str()) {
+                    self.ui.room(&room, true);
+                    self.ui.conversation = Some(room.clone());
+                }
+                out.line(format!("{} joined {}", user, room));
+            }
+            Ok(ServerEvent::RoomLeft { room, user }) => {
+                if self.me.as_deref() == Some(user.as_str()) {
+                    self.ui.room(&room, false);
+                    if self.ui.conversation.as_deref() == Some(room.as_str()) {
+                        self.ui.conversation = None;
                     }
-                    else{
-                        println!("NOT FILE");
+                }
+                out.line(format!("{} left {}", user, room));
+            }
+            Ok(ServerEvent::Rooms { rooms }) => {
+                if std::mem::take(&mut self.quiet_rooms) {
+                    //back after a disconnect: into every room left behind, then what was said there meanwhile
+                    if let Some(catch_up) = self.catch_up.take() {
+                        for room in catch_up.rooms {
+                            if !rooms.iter().any(|info| info.name == room && info.joined) {
+                                send_request(&ClientRequest::JoinRoom { room: room.clone() }, stream).await?;
+                            }
+                            send_request(&ClientRequest::History { room, since: catch_up.since }, stream).await?;
+                        }
+                    }
+                } else {
+                    for room in &rooms {
+                        out.line(format!("{} ({} members, created by {}) {}", room.name, room.members, room.creator, room.topic.clone().unwrap_or_default()));
+                    }
+                }
+                self.ui.set_rooms(rooms.into_iter().map(|room| (room.name, room.joined)));
+            }
+            Ok(ServerEvent::Members { room, members }) => {
+                let members: Vec<String> = members.iter()
+                    .map(|m| if m.online { m.name.clone() } else { format!("{} (offline)", m.name) })
+                    .collect();
+                out.line(format!("{}: {}", room, members.join(", ")));
+            }
+            Ok(ServerEvent::History { room, messages }) => {
+                let missed: Vec<HistoryMessage> = messages.into_iter().filter(|msg| !self.seen_before(&room, msg)).collect();
+                if !missed.is_empty() {
+                    self.out.line(format!("Missed in {} while disconnected:", room));
+                    for msg in missed {
+                        self.out.line(format!("[{}] {}: {}", room, msg.from, msg.content));
+                    }
+                }
+            }
+            Ok(ServerEvent::Users { online }) => self.ui.set_users(online),
+            Ok(ServerEvent::Presence { user, online }) => self.ui.presence(&user, online),
+            Ok(ServerEvent::Delivery { to, status, .. }) => match status {
+                DeliveryStatus::Delivered => (),
+                DeliveryStatus::Queued => out.line(format!("{} is offline, the message will be delivered when they log in", to)),
+                DeliveryStatus::UnknownUser => out.line(format!("There is no user {}, message not sent", to)),
+                DeliveryStatus::Blocked => out.line(format!("{} has blocked you, message not sent", to)),
+                DeliveryStatus::QueueFull => out.line(format!("{} is offline and cannot take more messages, message not sent", to)),
+                DeliveryStatus::Offline => out.line(format!("{} is offline, file not sent", to)),
+            },
+            Ok(ServerEvent::FileOffer { from, transfer, filename, size, sha256 }) => {
+                if let Err(e) = transfers.offer(&from, &transfer, &filename, size, &sha256, stream).await {
+                    out.line(format!("Cannot receive {} from {}: {}", filename, from, e));
+                }
+            }
+            Ok(ServerEvent::FileChunk { from, transfer, offset, data }) => {
+                if let Err(e) = transfers.chunk(&from, &transfer, offset, &data, stream).await {
+                    out.line(format!("Download from {} failed: {}", from, e));
+                }
+            }
+            Ok(ServerEvent::FileCancelled { from, transfer }) => transfers.cancel(&from, &transfer).await,
+            Ok(ServerEvent::FileSeek { transfer, offset, until }) => {
+                if let Err(e) = transfers.seek(&transfer, offset, until, stream).await {
+                    out.line(format!("Sending {} failed: {}", transfer, e));
+                }
+            }
+            Ok(ServerEvent::FileGrant { transfer, until }) => {
+                if let Err(e) = transfers.grant(&transfer, until, stream).await {
+                    out.line(format!("Sending {} failed: {}", transfer, e));
+                }
+            }
+            Ok(ServerEvent::FileRejected { transfer, by }) => transfers.rejected(&transfer, &by),
+            Ok(ServerEvent::FileFinished { transfer, complete }) => transfers.finished(&transfer, complete).await,
+            Ok(ServerEvent::Hello { .. }) => match self.token.clone() {
+                Some(token) => {
+                    self.login = Login::Resuming;
+                    send_request(&ClientRequest::ResumeSession { token }, stream).await?;
+                }
+                None => self.ask(Login::Account),
+            },
+            Ok(ServerEvent::LoginFailed { reason, attempts_left, retry_after }) => {
+                let (register, name) = match std::mem::replace(&mut self.login, Login::Account) {
+                    Login::Sent { register, name } => (register, name),
+                    _ => (false, String::new()),
+                };
+                match reason {
+                    LoginFailure::InvalidToken => {
+                        self.token = None;
+                        self.out.line("Your session has ended, please log in again");
+                        self.ask(Login::Account);
+                    }
+                    LoginFailure::UnknownUser => {
+                        self.out.line(format!("There is no user {}, {} attempts left", name, attempts_left));
+                        self.ask(Login::Account);
+                    }
+                    LoginFailure::WrongPassword => {
+                        self.out.line(format!("Wrong password, {} attempts left", attempts_left));
+                        self.ask(Login::Password { register, name });
+                    }
+                    LoginFailure::UsernameTaken => {
+                        self.out.line(format!("{} is taken, please pick another name", name));
+                        self.ask(Login::Name { register: true });
+                    }
+                    LoginFailure::InvalidName => {
+                        self.out.line("Names may only contain letters and digits");
+                        self.ask(Login::Name { register: true });
+                    }
+                    LoginFailure::Locked => {
+                        let wait = span(retry_after.unwrap_or(0));
+                        self.out.line(format!("Too many failed logins, try again in {}, {} attempts left", wait, attempts_left));
+                        self.ask(Login::Account);
+                    }
+                    LoginFailure::TooManyAttempts => self.out.line("Too many failed attempts"),
+                }
+            }
+            //only servers talking to telnet users prompt, kept for older servers
+            Ok(ServerEvent::System(SystemNotice::Prompt { text })) => out.line(text),
+            Ok(ServerEvent::System(SystemNotice::Info { text })) => out.line(text),
+            Ok(ServerEvent::System(SystemNotice::Welcome { name })) => {
+                out.line(format!("Welcome {}", name));
+                self.ui.set_secret(false);
+                self.ui.connection = format!("logged in as {}", name);
+                self.login = Login::Done;
+                self.transfers.log_in(&name);
+                self.me = Some(name);
+                self.quiet_rooms = true;
+                send_request(&ClientRequest::ListRooms, stream).await?;
+                if let Err(e) = self.transfers.resume_all(stream).await {
+                    self.out.line(format!("Could not resume transfers: {}", e));
+                }
+            }
+            Ok(ServerEvent::Session { token, .. }) => self.token = Some(token),
+            Ok(ServerEvent::Sessions { sessions }) => {
+                let now = unix_now();
+                out.line("Sessions:");
+                for session in sessions {
+                    let current = if session.current { ", this one" } else { "" };
+                    out.line(format!("  {}  started {} ago, expires in {}{}", session.id, span(now.saturating_sub(session.created_at)), span(session.expires_at.saturating_sub(now)), current));
+                }
+            }
+            Ok(ServerEvent::Stats { queues, connections }) => {
+                out.line(format!("{} connections open, at most {} and {} per address:", connections.open, connections.max, connections.max_per_address));
+                for address in connections.addresses {
+                    out.line(format!("  {:<40} {}", address.address, address.connections));
+                }
+                out.line(format!("Outgoing queues of {} logged in connections:", queues.len()));
+                for queue in queues {
+                    out.line(format!("  {:<16} {} waiting, at most {}, {} dropped, {} spilled", queue.user, queue.depth, queue.peak, queue.dropped, queue.spilled));
+                }
+            }
+            Ok(ServerEvent::Error(error)) if error.kind == ErrorKind::UnsupportedVersion => return Ok(Some(Ended::Incompatible(error.message))),
+            Ok(ServerEvent::Error(error)) => out.line(format!("Error: {}", error.message)),
+            Err(e) => out.line(format!("Unreadable message from server ({}): {}", e, line)),
+        }
+        Ok(None)
+    }
 
+    //Moves the login on, asking its question
+    fn ask(&mut self, login: Login) {
+        let question = match &login {
+            Login::Account => "Do you have an account? Y/N",
+            Login::Name { .. } => "Username",
+            Login::Password { register: false, .. } => "Password",
+            Login::Password { register: true, .. } => "Choose a password",
+            _ => "",
+        };
+        if !question.is_empty() {
+            self.out.line(question);
+        }
+        self.ui.set_secret(matches!(login, Login::Password { .. }));
+        self.login = login;
+    }
+
+    //A line typed before login, the answer to the last question
+    async fn answer(&mut self, line: String, stream: &TcpStream) -> Result<()> {
+        let answer = line.trim().to_string();
+        match std::mem::replace(&mut self.login, Login::Done) {
+            Login::Account => match answer.to_ascii_lowercase().chars().next() {
+                Some('y') => self.ask(Login::Name { register: false }),
+                Some('n') => self.ask(Login::Name { register: true }),
+                _ => {
+                    self.out.line("Please answer Y or N");
+                    self.login = Login::Account;
+                }
+            },
+            Login::Name { register } if answer.is_empty() => self.ask(Login::Name { register }),
+            Login::Name { register } => self.ask(Login::Password { register, name: answer }),
+            Login::Password { register, name } => {
+                let request = match register {
+                    true => ClientRequest::Register { name: name.clone(), password: answer },
+                    false => ClientRequest::Login { name: name.clone(), password: answer },
+                };
+                self.ask(Login::Sent { register, name });
+                send_request(&request, stream).await?;
+            }
+            waiting => {
+                self.out.line("Logging in, one moment");
+                self.login = waiting;
+            }
+        }
+        Ok(())
+    }
+
+    //A submitted input line, returns true for /quit
+    async fn submit(&mut self, line: String, stream: &TcpStream) -> Result<bool> {
+        //before login, and always for passwords, lines answer the login questions
+        if self.ui.is_secret() || (self.me.is_none() && !line.starts_with('/')) {
+            self.answer(line, stream).await?;
+            return Ok(false);
+        }
+        let command = match commands::parse(&line, self.ui.conversation.is_some()) {
+            Ok(Parsed::Command(command)) => command,
+            Ok(Parsed::Text(text)) if text.is_empty() => return Ok(false),
+            Ok(Parsed::Text(text)) => match &self.ui.conversation {
+                Some(conversation) => Command::Msg { to: conversation.split(',').map(str::to_string).collect(), text: Some(text) },
+                None => {
+                    self.out.line("Nobody to send that to, start a conversation with /msg name");
+                    return Ok(false);
+                }
+            },
+            Err(e) => {
+                self.out.line(e.to_string());
+                return Ok(false);
+            }
+        };
+        self.run_command(command, stream).await
+    }
+
+    async fn run_command(&mut self, command: Command, stream: &TcpStream) -> Result<bool> {
+        let out = &self.out;
+        let transfers = &mut self.transfers;
+        let request = match command {
+            Command::Msg { to, text } => {
+                self.ui.conversation = Some(to.join(","));
+                match text {
+                    Some(content) => {
+                        //the server does not echo room messages, what was sent counts as seen
+                        if let Some(me) = self.me.clone() {
+                            for room in to.iter().filter(|to| to.starts_with('#')) {
+                                self.saw(room.clone(), me.clone(), content.clone());
+                            }
+                        }
+                        ClientRequest::Message { to, content, id: None }
                     }
-                    // print!("{}:{}",dest,msg);
-                    // println!("\n\n{}:{}:{}\n\n",dest,msg_type,msg);
-                    // if line.starts_with("SYS:") {
-                    //     let system_msg = line[4..].to_string();
-                    //     println!("System message: {}", system_msg);
-                    // } else {
-                    //     match serde_json::from_str::<Message>(&line) {
-                    //         Ok(Message::Text { destination, content }) => {
-                    //             println!("Message to {}: {}", destination, content);
-                    //         }
-                    //         Ok(Message::File { transfer }) => {
-                    //             save_to_file(&transfer).await?;
-                    //             println!("File {} saved.", transfer.filename);
-                    //         }
-                    //         Ok(Message::System { info }) => {
-                    //             println!("System message: {}", info);
-                    //         }
-                    //         Err(e) => {
-                    //             println!(" {}", line);
-                    //         }
-                    //     }
-                    // }
-                },
-                None => break,
+                    None => return Ok(false),
+                }
+            }
+            Command::Send { to, path } => {
+                if let Err(e) = transfers.send_file(to, &path, stream).await {
+                    out.line(format!("Could not send {}: {}", path, e));
+                }
+                return Ok(false);
+            }
+            Command::Create { room, topic } => ClientRequest::CreateRoom { room, topic },
+            Command::Join { room } => ClientRequest::JoinRoom { room },
+            Command::Leave { room } => match room.or_else(|| self.ui.conversation.clone().filter(|c| c.starts_with('#'))) {
+                Some(room) => ClientRequest::LeaveRoom { room },
+                None => {
+                    out.line("Not talking in a room, usage: /leave #room");
+                    return Ok(false);
+                }
             },
-            line = lines_from_stdin.next().fuse() => match line {//From stdin: Parses input, sends files or text messages based on the input
-                Some(line) => {
-                    let line = line?;
-                    println!("Sending input: {}", line);
-                    let (dest, msg_block) = match line.find(':') { //splits message between destionation and message
-                        None => {
-                                    //println!("NONE");
-                                    writer.write_all(line.as_bytes()).await?;
-                                    writer.write_all(b"\n").await?;
-                                    writer.flush().await?;
-                                    continue},
-                        Some(idx) => (&line[..idx], line[idx + 1 ..].trim()),
-                    };
-                    let (msg_type, msg) = match msg_block.find(':') {
-                        None => {println!("FAILED");continue},
-                        Some(idx) => (&msg_block[..idx], msg_block[idx + 1 ..].trim()),
+            Command::Rooms => ClientRequest::ListRooms,
+            Command::Who { room: Some(room) } => ClientRequest::ListMembers { room },
+            Command::Who { room: None } => {
+                out.line(format!("Online: {}", self.ui.online().join(", ")));
+                return Ok(false);
+            }
+            Command::Block { user } => ClientRequest::Block { user },
+            Command::Unblock { user } => ClientRequest::Unblock { user },
+            //file offers are answered here, the server only hears the answer
+            Command::Offers => {
+                transfers.list_offers();
+                return Ok(false);
+            }
+            Command::Accept { offer } => {
+                if let Err(e) = transfers.accept(&offer, stream).await {
+                    out.line(format!("Cannot accept offer {}: {}", offer, e));
+                }
+                return Ok(false);
+            }
+            Command::Reject { offer } => {
+                if let Err(e) = transfers.reject(&offer, stream).await {
+                    out.line(format!("Cannot reject offer {}: {}", offer, e));
+                }
+                return Ok(false);
+            }
+            Command::Sessions => ClientRequest::ListSessions,
+            Command::Revoke { session } => ClientRequest::RevokeSession { id: session },
+            Command::Unlock { target } => ClientRequest::Unlock { target },
+            Command::Stats => ClientRequest::Stats,
+            Command::Help { command } => {
+                self.help(command.as_deref());
+                return Ok(false);
+            }
+            Command::Quit => return Ok(true),
+        };
+        send_request(&request, stream).await?;
+        Ok(false)
+    }
+
+    fn help(&self, command: Option<&str>) {
+        match commands::help(command) {
+            Ok(lines) => lines.into_iter().for_each(|line| self.out.line(line)),
+            Err(e) => self.out.line(e.to_string()),
+        }
+    }
+
+    //Forgets the connection, keeping what is needed to catch up once back
+    fn disconnected(&mut self) {
+        if self.me.take().is_some() {
+            let now = unix_now().saturating_sub(CATCH_UP_MARGIN);
+            //a catch up that never happened still counts from its own disconnect
+            let since = self.catch_up.as_ref().map_or(now, |catch_up| catch_up.since.min(now));
+            self.catch_up = Some(CatchUp { rooms: self.ui.joined_rooms(), since });
+        }
+        self.ui.set_users(Vec::new());
+        self.ui.set_secret(false);
+        self.quiet_rooms = false;
+    }
+
+    //Waits out the delay before the next attempt, counting down in the status bar.
+    //Returns true if the user quit meanwhile
+    async fn wait(&mut self, delay: Duration, attempt: u32, input: &mut (impl Stream<Item = io::Result<Input>> + Unpin)) -> Result<bool> {
+        let until = Instant::now() + delay;
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(false);
+            }
+            self.ui.connection = format!("disconnected, reconnecting in {}s (attempt {})", left.as_secs_f64().ceil(), attempt);
+            self.draw()?;
+            select! {
+                _ = task::sleep(left.min(Duration::from_secs(1))).fuse() => (),
+                event = input.next().fuse() => {
+                    let line = match event {
+                        Some(Ok(Input::Key(event))) => match self.ui.handle(event) {
+                            Action::Submit(line) => line,
+                            Action::Quit => return Ok(true),
+                            Action::None => String::new(),
+                        },
+                        Some(Ok(Input::Line(line))) => line,
+                        Some(Err(e)) => return Err(e.into()),
+                        None => return Ok(true),
                     };
-                    if msg_type.eq("file"){
-                        send_file(dest, msg, &stream).await?}
-                    else{
-                        // println!("NOT FILE");
-                        writer.write_all(line.as_bytes()).await?;
-                        writer.write_all(b"\n").await?;
-                        writer.flush().await?;
+                    //only what needs no server works meanwhile
+                    match commands::parse(&line, self.ui.conversation.is_some()) {
+                        Ok(Parsed::Command(Command::Quit)) => return Ok(true),
+                        Ok(Parsed::Command(Command::Help { command })) => self.help(command.as_deref()),
+                        Ok(Parsed::Text(text)) if text.is_empty() => (),
+                        Err(e) => self.out.line(e.to_string()),
+                        Ok(_) => self.out.line("Not connected, that was not sent"),
                     }
-                    // println!("\n\n{}:{}:{}\n\n",dest,msg_type,msg);
-
                 }
-                None => break,
             }
         }
     }
-    Ok(())
+
+    fn saw(&mut self, room: String, from: String, content: String) {
+        if self.seen.len() == SEEN {
+            self.seen.pop_front();
+        }
+        self.seen.push_back((room, from, content));
+    }
+
+    fn seen_before(&mut self, room: &str, msg: &HistoryMessage) -> bool {
+        let position = self.seen.iter().position(|(seen_room, from, content)| seen_room == room && *from == msg.from && *content == msg.content);
+        position.and_then(|position| self.seen.remove(position)).is_some()
+    }
+
+    fn draw(&mut self) -> Result<()> {
+        while let Ok(Some(line)) = self.output.try_next() {
+            self.ui.push(line);
+        }
+        self.ui.draw()?;
+        Ok(())
+    }
 }
 
-async fn send_file(destination: &str, filename: &str, stream: &TcpStream) -> Result<()> {
-    let mut writer = stream;
-    let mut file = File::open(filename).await?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).await?;
-
-    let file_transfer = FileTransfer {
-        destination: destination.to_string(),
-        filename: filename.to_string(),
-        data: buffer,
+//A rough length of time, like "3 days"
+fn span(secs: u64) -> String {
+    let (count, unit) = match secs {
+        0..=59 => return "less than a minute".to_string(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
     };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
 
-    let message = Message::File {
-        transfer: file_transfer,
-    };
-    let serialized = serde_json::to_string(&message)?;
-    writer.write_all(serialized.as_bytes()).await?;
-    writer.write_all(b"\n").await?;
-    writer.flush().await?;
-    println!("File {} sent to {}.", filename, destination);
-    Ok(())
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
-// async fn send_text(destination: &str, content: &str, writer: &mut TcpStream) -> Result<()> {
-//     let message = Message::Text { destination: destination.to_string(), content: content.to_string() };
-//     let serialized = serde_json::to_string(&message)?;
-//     writer.write_all(serialized.as_bytes()).await?;
-//     writer.write_all(b"\n").await?;
-//     writer.flush().await?;
-//     println!("Text sent to {}: {}", destination, content);
-//     Ok(())
-// }
-
-// async fn send_system_message(destination: &str, info: &str, writer: &mut TcpStream) -> Result<()> {
-//     let message = Message::System { info: info.to_string() };
-//     let serialized = serde_json::to_string(&message)?;
-//     writer.write_all(serialized.as_bytes()).await?;
-//     writer.write_all(b"\n").await?;
-//     writer.flush().await?;
-//     println!("System message sent to {}: {}", destination, info);
-//     Ok(())
-// }
-
-async fn save_to_file(file_transfer: &FileTransfer) -> Result<()> {
-    let mut file = File::create("./sentfile").await?;
-    file.write_all(&file_transfer.data).await?;
+async fn send_request(request: &ClientRequest, stream: &TcpStream) -> Result<()> {
+    let mut writer = stream;
+    writer.write_all(protocol::encode(request)?.as_bytes()).await?;
+    writer.flush().await?;
     Ok(())
 }
-//send file format: user:file:/path/to/file.txt
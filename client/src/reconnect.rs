@@ -0,0 +1,66 @@
+// Delays between attempts to reach the server again.
+//
+// The delay doubles with every failed attempt up to a ceiling, and each one is
+// picked at random from the upper half of its range so clients dropped by the
+// same restart do not all come back in the same instant.
+
+use std::time::Duration;
+
+pub struct Backoff {
+    first: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(first: Duration, max: Duration) -> Backoff {
+        Backoff {
+            first,
+            max,
+            attempt: 0,
+        }
+    }
+
+    //Attempts made since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    //Starts over, once a connection got as far as a login
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .first
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt += 1;
+        let half = ceiling / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_ceiling() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        let ceilings = [1, 2, 4, 8, 16, 30, 30, 30];
+        for ceiling in ceilings {
+            let ceiling = Duration::from_secs(ceiling);
+            let delay = backoff.next_delay();
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?} for {:?}", delay, ceiling);
+        }
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_secs(30));
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
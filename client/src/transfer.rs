@@ -0,0 +1,707 @@
+// File transfers on the client side.
+//
+// Sending announces the file with its size and SHA-256 digest, then sends
+// chunks from wherever the server asks with `FileSeek`, which is also how an
+// interrupted transfer picks up again. Only as much is sent as the server
+// grants, it lets the sender a few chunks ahead of what the recipients acked.
+// Incoming files are offers until the user accepts them. An accepted file is
+// written to `<name>.part` in the downloads directory and only renamed once the
+// digest matches, so a partial or corrupt download never has the real name.
+// Offered names are reduced to a plain file name and never overwrite an
+// existing file.
+//
+// Every unfinished transfer has a small JSON record in a directory of its own for
+// each server and account, under the user's state directory. After a reconnect,
+// or a restart of the client, the outgoing files of the account that logged in
+// are announced again and its incoming ones resume from the length of their part
+// file.
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::fs::{self, File, OpenOptions};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use chat_common::protocol::{ClientRequest, CHUNK_SIZE};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::ui::Output;
+use crate::{send_request, Result};
+
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    transfer: String,
+    path: PathBuf,
+    to: Vec<String>,
+    filename: String,
+    size: u64,
+    sha256: String,
+    #[serde(skip)]
+    next: u64, //offset of the next chunk to send
+    #[serde(skip)]
+    until: u64, //what the server lets us send for now
+}
+
+#[derive(Serialize, Deserialize)]
+struct Incoming {
+    from: String,
+    transfer: String,
+    size: u64,
+    sha256: String,
+    part: PathBuf,
+    target: PathBuf,
+}
+
+//An offer the user has not answered yet
+struct Pending {
+    number: usize,
+    from: String,
+    transfer: String,
+    filename: String,
+    size: u64,
+    sha256: String,
+}
+
+struct Download {
+    info: Incoming,
+    record: PathBuf,
+    file: File,
+    hasher: Sha256,
+    received: u64,
+}
+
+//Size and hex SHA-256 digest of a file
+async fn digest(path: &Path) -> Result<(u64, String)> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let size = hash_into(&mut file, &mut hasher).await?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+async fn hash_into(file: &mut File, hasher: &mut Sha256) -> Result<u64> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(size);
+        }
+        hasher.update(&buffer[..n]);
+        size += n as u64;
+    }
+}
+
+//Transfer ids only have to be unique among our own transfers
+fn transfer_id(sha256: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{}-{:x}", &sha256[..8], nanos)
+}
+
+//Names and ids come from other users, only the harmless characters make it into file names
+fn record_name(parts: &[&str]) -> String {
+    let name: String = parts
+        .join("-")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    format!("{}.json", name)
+}
+
+//A directory name for a server address or an account, `127.0.0.1:8080` becomes `127_0_0_1_8080`
+pub fn dir_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+//Where the transfer records of every server and account go, the user's state
+//directory or `.chat-transfers` in the working directory without one
+pub fn default_state_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from));
+    match base {
+        Some(base) => base.join("chat-client"),
+        None => PathBuf::from(".chat-transfers"),
+    }
+}
+
+async fn save_record<T: Serialize>(path: &Path, record: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    fs::write(path, serde_json::to_vec(record)?).await?;
+    Ok(())
+}
+
+async fn load_record<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let bytes = fs::read(path).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+//Keeps the last component of an offered name and drops whatever could lead
+//out of the downloads directory or hide the file
+fn safe_name(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !":*?\"<>|".contains(*c))
+        .take(128)
+        .collect();
+    let name = name.trim().trim_start_matches('.').trim();
+    if name.is_empty() {
+        "download".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+//`name` in `dir`, or `name (1)`, `name (2)`, ... if that is taken by a file or a download
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let taken = |path: &Path| path.exists() || part_path(path).exists();
+    let path = dir.join(name);
+    if !taken(&path) {
+        return path;
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(idx) if idx > 0 => (&name[..idx], &name[idx..]),
+        _ => (name, ""),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !taken(path))
+        .unwrap_or(path)
+}
+
+fn part_path(target: &Path) -> PathBuf {
+    let mut part = target.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+//Opens the part file of a download, hashing whatever it holds already
+async fn open_download(info: Incoming, record: PathBuf) -> Result<Download> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&info.part)
+        .await?;
+    let mut hasher = Sha256::new();
+    let mut received = hash_into(&mut file, &mut hasher).await?;
+    if received > info.size {
+        //not ours or garbage, start over
+        file.set_len(0).await?;
+        hasher = Sha256::new();
+        received = 0;
+    }
+    Ok(Download {
+        info,
+        record,
+        file,
+        hasher,
+        received,
+    })
+}
+
+//Unfinished uploads by transfer id, unanswered offers and downloads by sender and transfer id
+pub struct Transfers {
+    out: Output,
+    downloads_dir: PathBuf,
+    server_dir: PathBuf, //records for the server we talk to, one directory per account
+    state_dir: PathBuf,  //records of the account logged in, `server_dir` until the first login
+    max_size: u64,
+    uploads: HashMap<String, Upload>,
+    offers: Vec<Pending>,
+    next_offer: usize,
+    downloads: HashMap<(String, String), Download>,
+}
+
+impl Transfers {
+    //`max_size` is the largest file offered that is not rejected right away,
+    //`server_dir` holds the records for the server the client connects to
+    pub fn new(downloads_dir: PathBuf, server_dir: PathBuf, max_size: u64, out: Output) -> Self {
+        Transfers {
+            out,
+            downloads_dir,
+            state_dir: server_dir.clone(),
+            server_dir,
+            max_size,
+            uploads: HashMap::new(),
+            offers: Vec::new(),
+            next_offer: 1,
+            downloads: HashMap::new(),
+        }
+    }
+
+    //Switches to the records of `account`. The transfers of another account
+    //logged in before stay on disk for its next login.
+    pub fn log_in(&mut self, account: &str) {
+        let state_dir = self.server_dir.join(dir_name(account));
+        if state_dir != self.state_dir {
+            self.uploads.clear();
+            self.offers.clear();
+            self.downloads.clear();
+            self.state_dir = state_dir;
+        }
+    }
+
+    fn record_path(&self, parts: &[&str]) -> PathBuf {
+        self.state_dir.join(record_name(parts))
+    }
+
+    pub async fn send_file(
+        &mut self,
+        to: Vec<String>,
+        path: &str,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        let path: PathBuf = fs::canonicalize(path).await?.into();
+        let (size, sha256) = digest(&path).await?;
+        //only the name is sent, the recipient has no use for our directory layout
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let upload = Upload {
+            transfer: transfer_id(&sha256),
+            path,
+            to,
+            filename,
+            size,
+            sha256,
+            next: 0,
+            until: 0,
+        };
+        save_record(&self.record_path(&["out", &upload.transfer]), &upload).await?;
+        announce(&upload, stream).await?;
+        self.uploads.insert(upload.transfer.clone(), upload);
+        Ok(())
+    }
+
+    //The server wants the chunks of `transfer` from `offset` on, up to `until`
+    pub async fn seek(
+        &mut self,
+        transfer: &str,
+        offset: u64,
+        until: u64,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        if let Some(upload) = self.uploads.get_mut(transfer) {
+            upload.next = offset;
+            upload.until = until;
+        }
+        self.pump(transfer, stream).await
+    }
+
+    //The recipients caught up, `transfer` may go on up to `until`
+    pub async fn grant(&mut self, transfer: &str, until: u64, stream: &TcpStream) -> Result<()> {
+        if let Some(upload) = self.uploads.get_mut(transfer) {
+            upload.until = upload.until.max(until);
+        }
+        self.pump(transfer, stream).await
+    }
+
+    //Sends whatever of `transfer` is granted and not sent yet
+    async fn pump(&mut self, transfer: &str, stream: &TcpStream) -> Result<()> {
+        let upload = match self.uploads.get_mut(transfer) {
+            Some(upload) => upload,
+            None => return Ok(()),
+        };
+        let end = upload.until.min(upload.size);
+        if upload.next >= end {
+            return Ok(());
+        }
+        let mut file = File::open(&upload.path).await?;
+        file.seek(SeekFrom::Start(upload.next)).await?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        while upload.next < end {
+            let want = CHUNK_SIZE.min((end - upload.next) as usize);
+            let n = file.read(&mut buffer[..want]).await?;
+            if n == 0 {
+                return Err(
+                    format!("{} shrank while it was being sent", upload.path.display()).into(),
+                );
+            }
+            let chunk = ClientRequest::FileChunk {
+                transfer: transfer.to_string(),
+                offset: upload.next,
+                data: buffer[..n].to_vec(),
+            };
+            send_request(&chunk, stream).await?;
+            upload.next += n as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn finished(&mut self, transfer: &str, complete: bool) {
+        let _ = fs::remove_file(self.record_path(&["out", transfer])).await;
+        if let Some(upload) = self.uploads.remove(transfer) {
+            if complete {
+                self.out.line(format!(
+                    "File {} ({} bytes) sent to {}",
+                    upload.path.display(),
+                    upload.size,
+                    upload.to.join(",")
+                ));
+            } else {
+                self.out.line(format!(
+                    "Stopped sending {}, not every recipient accepted it in time",
+                    upload.path.display()
+                ));
+            }
+        }
+    }
+
+    pub fn rejected(&self, transfer: &str, by: &str) {
+        if let Some(upload) = self.uploads.get(transfer) {
+            self.out
+                .line(format!("{} declined {}", by, upload.path.display()));
+        }
+    }
+
+    pub async fn offer(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        filename: &str,
+        size: u64,
+        sha256: &str,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        let key = (from.to_string(), transfer.to_string());
+        if self.downloads.contains_key(&key)
+            || self
+                .offers
+                .iter()
+                .any(|offer| offer.from == from && offer.transfer == transfer)
+        {
+            return Ok(()); //offered again on login, answered or still asked already
+        }
+        //a record of the same file means we accepted it before and were cut off
+        let record = self.record_path(&["in", from, transfer]);
+        if let Some(info) = load_record::<Incoming>(&record).await {
+            if info.sha256 == sha256 && info.size == size {
+                let download = open_download(info, record).await?;
+                self.out.line(format!(
+                    "Resuming {} from {} at {} of {} bytes",
+                    filename, from, download.received, size
+                ));
+                return self.start_download(download, stream).await;
+            }
+        }
+        if size > self.max_size {
+            self.out.line(format!(
+                "{} offered {} ({} bytes), rejected as it is over {} bytes",
+                from, filename, size, self.max_size
+            ));
+            let reject = ClientRequest::FileReject {
+                from: from.to_string(),
+                transfer: transfer.to_string(),
+            };
+            return send_request(&reject, stream).await;
+        }
+        let number = self.next_offer;
+        self.next_offer += 1;
+        self.out.line(format!(
+            "Offer {}: {} wants to send you {} ({} bytes), /accept {} or /reject {}",
+            number, from, filename, size, number, number
+        ));
+        self.offers.push(Pending {
+            number,
+            from: from.to_string(),
+            transfer: transfer.to_string(),
+            filename: filename.to_string(),
+            size,
+            sha256: sha256.to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn list_offers(&self) {
+        if self.offers.is_empty() {
+            self.out.line("No open offers");
+        }
+        for offer in &self.offers {
+            self.out.line(format!(
+                "Offer {}: {} from {} ({} bytes)",
+                offer.number, offer.filename, offer.from, offer.size
+            ));
+        }
+    }
+
+    fn take_offer(&mut self, number: &str) -> Result<Pending> {
+        let idx = number
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| self.offers.iter().position(|offer| offer.number == number))
+            .ok_or_else(|| format!("there is no offer {}", number))?;
+        Ok(self.offers.remove(idx))
+    }
+
+    pub async fn accept(&mut self, number: &str, stream: &TcpStream) -> Result<()> {
+        let offer = self.take_offer(number)?;
+        fs::create_dir_all(&self.downloads_dir).await?;
+        let target = free_path(&self.downloads_dir, &safe_name(&offer.filename));
+        let info = Incoming {
+            part: part_path(&target),
+            target,
+            from: offer.from,
+            transfer: offer.transfer,
+            size: offer.size,
+            sha256: offer.sha256,
+        };
+        let record = self.record_path(&["in", &info.from, &info.transfer]);
+        save_record(&record, &info).await?;
+        self.out.line(format!(
+            "Receiving {} ({} bytes) from {}",
+            info.target.display(),
+            info.size,
+            info.from
+        ));
+        let download = open_download(info, record).await?;
+        self.start_download(download, stream).await
+    }
+
+    pub async fn reject(&mut self, number: &str, stream: &TcpStream) -> Result<()> {
+        let offer = self.take_offer(number)?;
+        let reject = ClientRequest::FileReject {
+            from: offer.from,
+            transfer: offer.transfer,
+        };
+        send_request(&reject, stream).await
+    }
+
+    //Tells the server how much we have, which for a fresh download accepts it
+    async fn start_download(&mut self, download: Download, stream: &TcpStream) -> Result<()> {
+        let (from, transfer) = (download.info.from.clone(), download.info.transfer.clone());
+        let request = if download.received == 0 {
+            ClientRequest::FileAccept {
+                from: from.clone(),
+                transfer: transfer.clone(),
+            }
+        } else {
+            ClientRequest::FileResume {
+                from: from.clone(),
+                transfer: transfer.clone(),
+                offset: download.received,
+            }
+        };
+        send_request(&request, stream).await?;
+        if download.received == download.info.size {
+            return finish(download, &self.out).await;
+        }
+        self.downloads.insert((from, transfer), download);
+        Ok(())
+    }
+
+    pub async fn chunk(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        offset: u64,
+        data: &[u8],
+        stream: &TcpStream,
+    ) -> Result<()> {
+        let key = (from.to_string(), transfer.to_string());
+        let download = match self.downloads.get_mut(&key) {
+            Some(download) => download,
+            None => return Ok(()), //offer failed earlier, that was reported then
+        };
+        let end = offset + data.len() as u64;
+        if offset > download.received {
+            //something went missing, ask for it again
+            let resume = ClientRequest::FileResume {
+                from: from.to_string(),
+                transfer: transfer.to_string(),
+                offset: download.received,
+            };
+            return send_request(&resume, stream).await;
+        }
+        if end > download.info.size {
+            return Err(format!("unexpected chunk at offset {}", offset).into());
+        }
+        if end > download.received {
+            let data = &data[(download.received - offset) as usize..];
+            download.file.write_all(data).await?;
+            download.hasher.update(data);
+            download.received = end;
+        }
+        //lets the sender go on, the server holds it back otherwise
+        let ack = ClientRequest::FileAck {
+            from: from.to_string(),
+            transfer: transfer.to_string(),
+            offset: download.received,
+        };
+        send_request(&ack, stream).await?;
+        if download.received == download.info.size {
+            if let Some(download) = self.downloads.remove(&key) {
+                return finish(download, &self.out).await;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn cancel(&mut self, from: &str, transfer: &str) {
+        if let Some(idx) = self
+            .offers
+            .iter()
+            .position(|offer| offer.from == from && offer.transfer == transfer)
+        {
+            let offer = self.offers.remove(idx);
+            self.out.line(format!(
+                "{} withdrew offer {} ({})",
+                from, offer.number, offer.filename
+            ));
+            return;
+        }
+        let record = self.record_path(&["in", from, transfer]);
+        let info = match self
+            .downloads
+            .remove(&(from.to_string(), transfer.to_string()))
+        {
+            Some(download) => Some(download.info),
+            None => load_record::<Incoming>(&record).await,
+        };
+        let _ = fs::remove_file(&record).await;
+        if let Some(info) = info {
+            let _ = fs::remove_file(&info.part).await;
+            self.out.line(format!(
+                "{} stopped sending {}",
+                from,
+                info.target.display()
+            ));
+        }
+    }
+
+    //After login: announces our unfinished uploads again and reports how far
+    //the unfinished downloads got
+    pub async fn resume_all(&mut self, stream: &TcpStream) -> Result<()> {
+        let mut entries = match fs::read_dir(&self.state_dir).await {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        while let Some(entry) = entries.next().await {
+            let path: PathBuf = entry?.path().into();
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if name.starts_with("out-") {
+                if let Some(upload) = load_record::<Upload>(&path).await {
+                    if self.uploads.contains_key(&upload.transfer) {
+                        continue;
+                    }
+                    self.out.line(format!(
+                        "Resuming {} for {}",
+                        upload.path.display(),
+                        upload.to.join(",")
+                    ));
+                    announce(&upload, stream).await?;
+                    self.uploads.insert(upload.transfer.clone(), upload);
+                }
+            } else if name.starts_with("in-") {
+                if let Some(info) = load_record::<Incoming>(&path).await {
+                    let key = (info.from.clone(), info.transfer.clone());
+                    if self.downloads.contains_key(&key) {
+                        continue;
+                    }
+                    let download = open_download(info, path).await?;
+                    self.start_download(download, stream).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn announce(upload: &Upload, stream: &TcpStream) -> Result<()> {
+    let start = ClientRequest::FileStart {
+        transfer: upload.transfer.clone(),
+        to: upload.to.clone(),
+        filename: upload.filename.clone(),
+        size: upload.size,
+        sha256: upload.sha256.clone(),
+        id: None,
+    };
+    send_request(&start, stream).await
+}
+
+//Checks the digest and gives the file its real name
+async fn finish(mut download: Download, out: &Output) -> Result<()> {
+    download.file.flush().await?;
+    drop(download.file);
+    let info = download.info;
+    let _ = fs::remove_file(&download.record).await;
+    let digest = format!("{:x}", download.hasher.finalize());
+    if digest != info.sha256 {
+        fs::remove_file(&info.part).await?;
+        return Err(format!(
+            "{} is corrupt, its SHA-256 digest does not match",
+            info.target.display()
+        )
+        .into());
+    }
+    //something may have taken the name since the download started
+    let mut target = info.target;
+    if target.exists() {
+        let name = target
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        target = free_path(target.parent().unwrap_or(Path::new(".")), &name);
+    }
+    fs::rename(&info.part, &target).await?;
+    out.line(format!(
+        "File {} ({} bytes) saved",
+        target.display(),
+        info.size
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offered_names_stay_in_the_downloads_directory() {
+        assert_eq!(safe_name("../../etc/passwd"), "passwd");
+        assert_eq!(safe_name("C:\\Users\\me\\report.pdf"), "report.pdf");
+        assert_eq!(safe_name(".."), "download");
+        assert_eq!(safe_name(".bashrc"), "bashrc");
+        assert_eq!(safe_name("a\nb<c>.txt"), "abc.txt");
+    }
+
+    #[test]
+    fn taken_names_get_a_number() {
+        let dir = std::env::temp_dir().join(format!("chat-downloads-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(free_path(&dir, "notes.txt"), dir.join("notes.txt"));
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+        std::fs::write(dir.join("notes (1).txt.part"), "").unwrap();
+        assert_eq!(free_path(&dir, "notes.txt"), dir.join("notes (2).txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_are_kept_per_server_and_account() {
+        let server_dir = Path::new("state").join(dir_name("127.0.0.1:8080"));
+        assert_eq!(server_dir, Path::new("state").join("127_0_0_1_8080"));
+        let (out, _output) = Output::channel();
+        let mut transfers = Transfers::new(PathBuf::from("downloads"), server_dir.clone(), 10, out);
+        transfers.log_in("alice");
+        let alice = transfers.record_path(&["out", "t1"]);
+        transfers.log_in("bob");
+        let bob = transfers.record_path(&["out", "t1"]);
+        assert_eq!(alice, server_dir.join("alice").join("out-t1.json"));
+        assert_eq!(bob, server_dir.join("bob").join("out-t1.json"));
+    }
+}
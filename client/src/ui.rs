@@ -0,0 +1,403 @@
+// Full-screen terminal UI.
+//
+// The side list of rooms and online users is on the left, the message pane on
+// the right, the status bar and the input line at the bottom. The whole screen
+// is redrawn after every change, it is small enough for that. When stdin or
+// stdout is not a terminal the client prints lines and reads stdin instead, so
+// it can still be scripted.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, Stdout, Write};
+use std::pin::Pin;
+
+use async_std::io::{stdin, BufReader};
+use async_std::prelude::*;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::tty::IsTty;
+use crossterm::{cursor, execute, queue};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::Stream;
+
+const SIDE_WIDTH: u16 = 20;
+const SCROLLBACK: usize = 2000;
+
+//Lines for the message pane, handed to whatever has something to say
+#[derive(Clone)]
+pub struct Output(UnboundedSender<String>);
+
+impl Output {
+    pub fn channel() -> (Output, UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded();
+        (Output(sender), receiver)
+    }
+
+    pub fn line(&self, text: impl Into<String>) {
+        let _ = self.0.unbounded_send(text.into());
+    }
+}
+
+pub enum Input {
+    Key(Event),
+    Line(String), //without a terminal
+}
+
+//Terminal events on a terminal, stdin lines otherwise
+pub fn input(full_screen: bool) -> Pin<Box<dyn Stream<Item = io::Result<Input>>>> {
+    if full_screen {
+        Box::pin(EventStream::new().map(|event| event.map(Input::Key)))
+    } else {
+        Box::pin(
+            BufReader::new(stdin())
+                .lines()
+                .map(|line| line.map(Input::Line)),
+        )
+    }
+}
+
+pub enum Action {
+    None,
+    Submit(String),
+    Quit,
+}
+
+pub struct Ui {
+    screen: Option<Stdout>, //None without a terminal
+    lines: VecDeque<String>,
+    scroll: usize, //rows scrolled back from the newest
+    input: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    browsing: Option<usize>, //position in `history` while going through it
+    secret: bool,
+    pub connection: String,
+    pub conversation: Option<String>,
+    rooms: BTreeMap<String, bool>, //room -> joined
+    users: BTreeSet<String>,
+}
+
+impl Ui {
+    pub fn start() -> io::Result<Ui> {
+        let screen = if io::stdin().is_tty() && io::stdout().is_tty() {
+            let mut stdout = io::stdout();
+            terminal::enable_raw_mode()?;
+            execute!(stdout, EnterAlternateScreen)?;
+            Some(stdout)
+        } else {
+            None
+        };
+        Ok(Ui {
+            screen,
+            lines: VecDeque::new(),
+            scroll: 0,
+            input: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            browsing: None,
+            secret: false,
+            connection: String::new(),
+            conversation: None,
+            rooms: BTreeMap::new(),
+            users: BTreeSet::new(),
+        })
+    }
+
+    pub fn is_full_screen(&self) -> bool {
+        self.screen.is_some()
+    }
+
+    pub fn push(&mut self, line: String) {
+        if self.screen.is_none() {
+            println!("{}", line);
+            return;
+        }
+        if self.scroll > 0 {
+            self.scroll += 1; //keep looking at the same lines
+        }
+        self.lines.push_back(line);
+        if self.lines.len() > SCROLLBACK {
+            self.lines.pop_front();
+        }
+    }
+
+    //Masks the input line, for passwords
+    pub fn set_secret(&mut self, secret: bool) {
+        self.secret = secret;
+    }
+
+    pub fn is_secret(&self) -> bool {
+        self.secret
+    }
+
+    pub fn online(&self) -> Vec<String> {
+        self.users.iter().cloned().collect()
+    }
+
+    pub fn set_users(&mut self, online: Vec<String>) {
+        self.users = online.into_iter().collect();
+    }
+
+    pub fn presence(&mut self, user: &str, online: bool) {
+        if online {
+            self.users.insert(user.to_string());
+        } else {
+            self.users.remove(user);
+        }
+    }
+
+    pub fn set_rooms(&mut self, rooms: impl IntoIterator<Item = (String, bool)>) {
+        self.rooms = rooms.into_iter().collect();
+    }
+
+    pub fn room(&mut self, room: &str, joined: bool) {
+        self.rooms.insert(room.to_string(), joined);
+    }
+
+    pub fn joined_rooms(&self) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter(|(_, &joined)| joined)
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    pub fn handle(&mut self, event: Event) -> Action {
+        let key = match event {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => return Action::None, //resizes only need the redraw
+        };
+        let KeyEvent {
+            code, modifiers, ..
+        } = key;
+        let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+        match code {
+            KeyCode::Char('c') if ctrl => return Action::Quit,
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => return Action::Quit,
+            KeyCode::Char('u') if ctrl => self.set_input(String::new()),
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.input.len(),
+            KeyCode::Char(c) if !ctrl => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Enter => {
+                let line: String = self.input.drain(..).collect();
+                self.cursor = 0;
+                self.browsing = None;
+                self.scroll = 0;
+                if !self.secret && !line.is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                let echo = if self.secret {
+                    "*".repeat(line.chars().count())
+                } else {
+                    line.clone()
+                };
+                self.push(format!("> {}", echo));
+                return Action::Submit(line);
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Esc => self.set_input(String::new()),
+            KeyCode::Up if !self.secret && !self.history.is_empty() => {
+                let idx = match self.browsing {
+                    Some(idx) => idx.saturating_sub(1),
+                    None => self.history.len() - 1,
+                };
+                self.browsing = Some(idx);
+                self.set_input(self.history[idx].clone());
+            }
+            KeyCode::Down if self.browsing.is_some() => {
+                let next = self
+                    .browsing
+                    .map(|idx| idx + 1)
+                    .filter(|&idx| idx < self.history.len());
+                self.browsing = next;
+                let line = next
+                    .map(|idx| self.history[idx].clone())
+                    .unwrap_or_default();
+                self.set_input(line);
+            }
+            KeyCode::PageUp => self.scroll += self.page(),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page()),
+            _ => (),
+        }
+        Action::None
+    }
+
+    fn set_input(&mut self, line: String) {
+        self.input = line.chars().collect();
+        self.cursor = self.input.len();
+    }
+
+    fn page(&self) -> usize {
+        let (_, height) = terminal::size().unwrap_or((80, 24));
+        (height as usize / 2).max(1)
+    }
+
+    pub fn draw(&mut self) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let side = if width >= 60 { SIDE_WIDTH } else { 0 };
+        let pane_x = if side > 0 { side + 1 } else { 0 };
+        let pane_width = width.saturating_sub(pane_x) as usize;
+        let pane_height = height.saturating_sub(2) as usize;
+        if pane_width == 0 || height < 3 {
+            return Ok(());
+        }
+
+        //wrap from the newest line back until the pane and the scrolled part are full
+        let mut rows: Vec<String> = Vec::new();
+        for line in self.lines.iter().rev() {
+            let mut wrapped = wrap(line, pane_width);
+            wrapped.reverse();
+            rows.extend(wrapped);
+            if rows.len() >= pane_height + self.scroll {
+                break;
+            }
+        }
+        self.scroll = self.scroll.min(rows.len().saturating_sub(pane_height));
+        let visible: Vec<&String> = rows.iter().skip(self.scroll).take(pane_height).collect();
+
+        let side_rows = self.side_list();
+        let status = self.status();
+        let (input, cursor) = self.input_line(width as usize);
+        let stdout = match self.screen.as_mut() {
+            Some(stdout) => stdout,
+            None => return Ok(()),
+        };
+        queue!(stdout, cursor::Hide)?;
+        for row in 0..pane_height {
+            if side > 0 {
+                let entry = side_rows.get(row).map(String::as_str).unwrap_or("");
+                queue!(
+                    stdout,
+                    cursor::MoveTo(0, row as u16),
+                    Print(fit(entry, side as usize)),
+                    Print('│')
+                )?;
+            }
+            //the newest row is at the bottom of the pane
+            let line = pane_height
+                .checked_sub(row + 1)
+                .and_then(|idx| visible.get(idx))
+                .map(|line| line.as_str())
+                .unwrap_or("");
+            queue!(
+                stdout,
+                cursor::MoveTo(pane_x, row as u16),
+                Print(fit(line, pane_width))
+            )?;
+        }
+        queue!(
+            stdout,
+            cursor::MoveTo(0, height - 2),
+            SetAttribute(Attribute::Reverse),
+            Print(fit(&status, width as usize)),
+            SetAttribute(Attribute::Reset),
+            cursor::MoveTo(0, height - 1),
+            Print(fit(&input, width as usize)),
+            cursor::MoveTo(cursor as u16, height - 1),
+            cursor::Show
+        )?;
+        stdout.flush()
+    }
+
+    fn side_list(&self) -> Vec<String> {
+        let mut rows = vec!["Rooms".to_string()];
+        rows.extend(
+            self.rooms
+                .iter()
+                .map(|(room, &joined)| format!("{} {}", if joined { '*' } else { ' ' }, room)),
+        );
+        rows.push(String::new());
+        rows.push(format!("Online ({})", self.users.len()));
+        rows.extend(self.users.iter().map(|user| format!("  {}", user)));
+        rows
+    }
+
+    fn status(&self) -> String {
+        let conversation = match &self.conversation {
+            Some(conversation) => format!("talking to {}", conversation),
+            None => "no conversation, /msg name starts one".to_string(),
+        };
+        let scrolled = if self.scroll > 0 {
+            " | scrolled back, PgDn"
+        } else {
+            ""
+        };
+        format!(" {} | {}{}", self.connection, conversation, scrolled)
+    }
+
+    //The input line and the cursor column, scrolled sideways to keep the cursor visible
+    fn input_line(&self, width: usize) -> (String, usize) {
+        let prompt = "> ";
+        let text: Vec<char> = if self.secret {
+            vec!['*'; self.input.len()]
+        } else {
+            self.input.clone()
+        };
+        let room = width.saturating_sub(prompt.len() + 1).max(1);
+        let start = (self.cursor + 1).saturating_sub(room);
+        let shown: String = text.iter().skip(start).take(room).collect();
+        (
+            format!("{}{}", prompt, shown),
+            prompt.len() + self.cursor - start,
+        )
+    }
+}
+
+impl Drop for Ui {
+    fn drop(&mut self) {
+        if let Some(stdout) = self.screen.as_mut() {
+            let _ = execute!(stdout, LeaveAlternateScreen, cursor::Show);
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
+
+//Splits a line into rows of at most `width` characters
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().filter(|c| !c.is_control()).collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(width)
+        .map(|row| row.iter().collect())
+        .collect()
+}
+
+//Cuts or pads `text` to exactly `width` characters, so it overwrites the old row
+fn fit(text: &str, width: usize) -> String {
+    let mut row: String = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(width)
+        .collect();
+    let len = row.chars().count();
+    row.extend(std::iter::repeat(' ').take(width - len));
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_and_fits_rows() {
+        assert_eq!(wrap("abcdefg", 3), ["abc", "def", "g"]);
+        assert_eq!(wrap("", 3), [""]);
+        assert_eq!(fit("ab", 4), "ab  ");
+        assert_eq!(fit("abcdef", 4), "abcd");
+    }
+}
@@ -2,6 +2,8 @@
 // To make sure that other projects can access your code, everything must be publically exported from THIS file:
 // - Either you have `pub` methods here (like `add`), or you have public module declarations (`pub mod $WHATEVER`)
 
+pub mod protocol;
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -0,0 +1,543 @@
+// Wire protocol shared by the server and the client.
+//
+// Every frame is one JSON object on its own line. The client only ever sends
+// `ClientRequest`s and the server only ever sends `ServerEvent`s, so both sides
+// decode with the same types and cannot drift apart.
+//
+// A connection starts with `Hello`, then logs in with `Login`, `Register` or
+// `ResumeSession` until the server answers with `SystemNotice::Welcome`.
+
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever a change to the types below breaks older peers
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Largest payload of a single `FileChunk`, base64 makes the frame about a third bigger
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Longest line a `ClientRequest::FileChunk` can take: the base64 payload plus
+/// room for the rest of the frame with a transfer id of up to 64 characters
+pub const MAX_CHUNK_FRAME: usize = CHUNK_SIZE.div_ceil(3) * 4 + 256;
+
+/// Frames sent from a client to the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientRequest {
+    /// First frame of a connection with the client's `PROTOCOL_VERSION`.
+    /// Answered with `ServerEvent::Hello`, or an `UnsupportedVersion` error
+    /// after which the server hangs up.
+    Hello {
+        version: u32,
+    },
+    /// Logs in to an existing account
+    Login {
+        name: String,
+        password: String,
+    },
+    /// Creates an account and logs in to it
+    Register {
+        name: String,
+        password: String,
+    },
+    /// Logs in with the token of an earlier `ServerEvent::Session`
+    ResumeSession {
+        token: String,
+    },
+    /// Answer to the last `SystemNotice::Prompt`. Only the prompt login the
+    /// server can offer raw telnet users needs it, where a line that is not
+    /// JSON counts as one of these.
+    Input {
+        text: String,
+    },
+    /// Asks for a `ServerEvent::Sessions` with the client's own sessions
+    ListSessions,
+    /// Ends one of the client's sessions, connections logged in with it are closed
+    RevokeSession {
+        id: String,
+    },
+    /// Text message for one or more users or rooms. The optional `id` is
+    /// echoed back in every `ServerEvent::Delivery` for this message.
+    Message {
+        to: Vec<String>,
+        content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    /// Announces a file for one or more users, acknowledged like `Message`.
+    /// `transfer` is chosen by the sender and names the transfer in every
+    /// following frame, `sha256` is the hex digest of the whole file.
+    /// Chunks are sent once the server answers with `ServerEvent::FileSeek`.
+    /// Announcing the same transfer again after a reconnect resumes it.
+    FileStart {
+        transfer: String,
+        to: Vec<String>,
+        filename: String,
+        size: u64,
+        sha256: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    /// The next piece of a started file, at most `CHUNK_SIZE` bytes.
+    /// Chunks are sent in order, the transfer ends with the byte at `size`.
+    /// Nothing past the `until` of the last `FileSeek` or `FileGrant` is taken.
+    FileChunk {
+        transfer: String,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// Accepts an offered file, its chunks follow as `ServerEvent::FileChunk`
+    FileAccept {
+        from: String,
+        transfer: String,
+    },
+    /// Turns an offered file down, the sender gets a `ServerEvent::FileRejected`
+    FileReject {
+        from: String,
+        transfer: String,
+    },
+    /// Sent by a recipient that has the first `offset` bytes of an accepted
+    /// file, after a reconnect or when chunks went missing
+    FileResume {
+        from: String,
+        transfer: String,
+        offset: u64,
+    },
+    /// Sent by a recipient that wrote the first `offset` bytes of a file, the
+    /// sender is only let ahead of the slowest recipient by a few chunks
+    FileAck {
+        from: String,
+        transfer: String,
+        offset: u64,
+    },
+    /// Creates a room and joins it, room names start with `#`
+    CreateRoom {
+        room: String,
+        topic: Option<String>,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    /// Asks for a `ServerEvent::Rooms` with every room
+    ListRooms,
+    /// Asks for a `ServerEvent::Members` of one room
+    ListMembers {
+        room: String,
+    },
+    /// Asks for the messages posted to a room after `since` (seconds since
+    /// the unix epoch), answered with `ServerEvent::History`
+    History {
+        room: String,
+        since: u64,
+    },
+    /// Stops direct and room messages from `user` reaching the client
+    Block {
+        user: String,
+    },
+    Unblock {
+        user: String,
+    },
+    /// Lifts the login lockout of an account, or of a source address when
+    /// `target` is an IP address. Only admins may send it.
+    Unlock {
+        target: String,
+    },
+    /// Asks for a `ServerEvent::Stats`, only admins may send it
+    Stats,
+}
+
+/// Frames sent from the server to a client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// Notice generated by the server itself
+    System(SystemNotice),
+    /// Answer to `ClientRequest::Hello` with the server's `PROTOCOL_VERSION`
+    Hello { version: u32 },
+    /// A `Login`, `Register` or `ResumeSession` did not work. The client can
+    /// try again unless `reason` is `TooManyAttempts`, then the server hangs up.
+    /// `attempts_left` counts the wrong names and passwords still allowed,
+    /// `retry_after` is set for `Locked` with the seconds until the lock lifts.
+    LoginFailed {
+        reason: LoginFailure,
+        attempts_left: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+    /// A session started by a password login, `token` logs in with
+    /// `ClientRequest::ResumeSession` until `expires_at`
+    Session {
+        id: String,
+        token: String,
+        expires_at: u64,
+    },
+    /// Answer to `ClientRequest::ListSessions`, oldest first
+    Sessions { sessions: Vec<SessionInfo> },
+    /// Answer to `ClientRequest::Stats`, the outgoing queue of every logged in
+    /// connection and how many connections are open
+    Stats {
+        queues: Vec<QueueInfo>,
+        connections: ConnectionInfo,
+    },
+    /// Text message from another user
+    Message { from: String, content: String },
+    /// Text message posted to a room the client is a member of
+    RoomMessage {
+        room: String,
+        from: String,
+        content: String,
+    },
+    /// A user joined a room the client is a member of, including the client itself
+    RoomJoined { room: String, user: String },
+    /// A user left a room the client is a member of, including the client itself
+    RoomLeft { room: String, user: String },
+    /// Answer to `ClientRequest::ListRooms`
+    Rooms { rooms: Vec<RoomInfo> },
+    /// Answer to `ClientRequest::ListMembers`
+    Members { room: String, members: Vec<Member> },
+    /// Answer to `ClientRequest::History`, oldest first
+    History {
+        room: String,
+        messages: Vec<HistoryMessage>,
+    },
+    /// Everyone online, sent after `SystemNotice::Welcome` and kept current
+    /// with `Presence`
+    Users { online: Vec<String> },
+    /// A user logged in or out
+    Presence { user: String, online: bool },
+    /// Another user wants to send a file. Nothing arrives until the client
+    /// answers with `ClientRequest::FileAccept`, then the chunks follow as
+    /// `FileChunk`. Unanswered offers are sent again on login.
+    FileOffer {
+        from: String,
+        transfer: String,
+        filename: String,
+        size: u64,
+        sha256: String,
+    },
+    /// A piece of a file announced by `FileOffer`
+    FileChunk {
+        from: String,
+        transfer: String,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// The transfer was abandoned before the file was complete
+    FileCancelled { from: String, transfer: String },
+    /// Tells the sender to continue `transfer` with the chunk at `offset`,
+    /// sending no further than byte `until` for now
+    FileSeek {
+        transfer: String,
+        offset: u64,
+        until: u64,
+    },
+    /// Lets the sender go on with `transfer` up to byte `until`, where it is
+    FileGrant { transfer: String, until: u64 },
+    /// Tells the sender a recipient turned the file down
+    FileRejected { transfer: String, by: String },
+    /// Tells the sender the transfer is over, `complete` is false if it
+    /// expired before every recipient had the whole file or if every
+    /// recipient rejected it
+    FileFinished { transfer: String, complete: bool },
+    /// What happened to a sent message or file, one per destination
+    Delivery {
+        to: String,
+        status: DeliveryStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    /// The last request could not be handled
+    Error(ProtocolError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "notice", rename_all = "snake_case")]
+pub enum SystemNotice {
+    /// The server is waiting for a `ClientRequest::Input`
+    Prompt { text: String },
+    /// Informational text, no answer expected
+    Info { text: String },
+    /// Login succeeded, chat messages can be sent from now on
+    Welcome { name: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Handed to the recipient's connection, or to every room member online
+    Delivered,
+    /// The recipient is not connected, it will get the message on login
+    Queued,
+    /// There is no account with that name
+    UnknownUser,
+    /// The recipient has blocked the sender
+    Blocked,
+    /// The recipient is not connected and has too many queued messages
+    QueueFull,
+    /// The recipient is not connected, files are not queued
+    Offline,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: Option<String>,
+    pub creator: String,
+    pub members: usize,
+    /// Whether the client asking is a member
+    #[serde(default)]
+    pub joined: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub online: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginFailure {
+    /// There is no account with that name
+    UnknownUser,
+    WrongPassword,
+    /// Registration picked a name that exists already
+    UsernameTaken,
+    /// Names may only contain letters and digits
+    InvalidName,
+    /// The session token is unknown, expired or revoked
+    InvalidToken,
+    TooManyAttempts,
+    /// Too many wrong passwords for the account or from the client's address,
+    /// no password is checked until the lock lifts
+    Locked,
+}
+
+/// Events waiting to be sent to one connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueInfo {
+    pub user: String,
+    pub depth: usize,
+    /// Deepest the queue got
+    pub peak: usize,
+    /// Events lost to the `drop_oldest` overflow policy
+    pub dropped: u64,
+    /// Events written to disk by the `spill` overflow policy
+    pub spilled: u64,
+}
+
+/// Open connections, logged in or not, and the limits they are held to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub open: usize,
+    pub max: usize,
+    pub max_per_address: usize,
+    /// Every address with a connection open, most connections first
+    pub addresses: Vec<AddressInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressInfo {
+    pub address: String,
+    pub connections: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Seconds since the unix epoch, like `expires_at`
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Whether the connection asking is logged in with this session
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryMessage {
+    pub from: String,
+    pub content: String,
+    /// Seconds since the unix epoch
+    pub sent_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProtocolError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The frame was not valid JSON or not a known request
+    Malformed,
+    /// The request is valid but not allowed right now
+    Unexpected,
+    /// The request is valid but the server does not support it
+    Unsupported,
+    /// Registration lost the race for a username
+    UsernameTaken,
+    /// The frame is longer than the server accepts
+    TooLarge,
+    /// The server is at its connection limit
+    ServerFull,
+    /// The client's address has as many connections open as the server allows one address
+    TooManyConnections,
+    /// The client did not log in in time and is disconnected
+    LoginTimeout,
+    /// A room or user name contains characters that are not allowed
+    InvalidName,
+    NoSuchRoom,
+    RoomExists,
+    /// Posting to or leaving a room the client has not joined
+    NotMember,
+    /// There is no account with that name
+    NoSuchUser,
+    /// The account has a session already and the server refuses a second one
+    AlreadyLoggedIn,
+    /// A file chunk names a transfer that was never started or is finished
+    NoSuchTransfer,
+    /// A file chunk is out of order, too big or runs past the announced size
+    InvalidChunk,
+    /// The client's `PROTOCOL_VERSION` is not the server's
+    UnsupportedVersion,
+    /// The client has no session with that id
+    NoSuchSession,
+    /// Only admins may send the request
+    Forbidden,
+}
+
+impl ServerEvent {
+    pub fn prompt(text: impl Into<String>) -> Self {
+        ServerEvent::System(SystemNotice::Prompt { text: text.into() })
+    }
+
+    pub fn info(text: impl Into<String>) -> Self {
+        ServerEvent::System(SystemNotice::Info { text: text.into() })
+    }
+
+    pub fn error(kind: ErrorKind, message: impl Into<String>) -> Self {
+        ServerEvent::Error(ProtocolError {
+            kind,
+            message: message.into(),
+        })
+    }
+}
+
+//File data travels as a base64 string rather than a JSON array of numbers
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serializes a frame, including the terminating newline
+pub fn encode<T: Serialize>(frame: &T) -> serde_json::Result<String> {
+    let mut line = serde_json::to_string(frame)?;
+    line.push('\n');
+    Ok(line)
+}
+
+/// Parses a single line received from the other side
+pub fn decode<'a, T: Deserialize<'a>>(line: &'a str) -> serde_json::Result<T> {
+    serde_json::from_str(line.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_requests_and_events() {
+        let request = ClientRequest::Message {
+            to: vec!["bob".to_string(), "carol".to_string()],
+            content: "hi: there".to_string(),
+            id: None,
+        };
+        let line = encode(&request).unwrap();
+        assert!(line.ends_with('\n'));
+        assert_eq!(decode::<ClientRequest>(&line).unwrap(), request);
+
+        let event = ServerEvent::prompt("Do you have an account? Y/N");
+        assert_eq!(
+            decode::<ServerEvent>(&encode(&event).unwrap()).unwrap(),
+            event
+        );
+
+        let delivery = ServerEvent::Delivery {
+            to: "bob".to_string(),
+            status: DeliveryStatus::UnknownUser,
+            id: Some(7),
+        };
+        assert_eq!(
+            encode(&delivery).unwrap(),
+            "{\"type\":\"delivery\",\"to\":\"bob\",\"status\":\"unknown_user\",\"id\":7}\n"
+        );
+    }
+
+    #[test]
+    fn file_chunks_are_base64() {
+        let chunk = ClientRequest::FileChunk {
+            transfer: "t1".to_string(),
+            offset: 0,
+            data: vec![0, 255, 10],
+        };
+        let line = encode(&chunk).unwrap();
+        assert!(line.contains("\"data\":\"AP8K\""));
+        assert_eq!(decode::<ClientRequest>(&line).unwrap(), chunk);
+        assert!(decode::<ClientRequest>(
+            r#"{"type":"file_chunk","transfer":"t1","offset":0,"data":"not base64!"}"#
+        )
+        .is_err());
+
+        //the biggest chunk a sender can make still fits
+        let biggest = ClientRequest::FileChunk {
+            transfer: "t".repeat(64),
+            offset: u64::MAX,
+            data: vec![255; CHUNK_SIZE],
+        };
+        assert!(encode(&biggest).unwrap().len() <= MAX_CHUNK_FRAME);
+    }
+
+    #[test]
+    fn handshake_frames() {
+        assert_eq!(
+            decode::<ClientRequest>(r#"{"type":"hello","version":2}"#).unwrap(),
+            ClientRequest::Hello { version: 2 }
+        );
+        assert_eq!(
+            decode::<ClientRequest>(r#"{"type":"login","name":"bob","password":"pw"}"#).unwrap(),
+            ClientRequest::Login {
+                name: "bob".to_string(),
+                password: "pw".to_string()
+            }
+        );
+        let failed = ServerEvent::LoginFailed {
+            reason: LoginFailure::WrongPassword,
+            attempts_left: 2,
+            retry_after: None,
+        };
+        assert_eq!(
+            encode(&failed).unwrap(),
+            "{\"type\":\"login_failed\",\"reason\":\"wrong_password\",\"attempts_left\":2}\n"
+        );
+    }
+
+    #[test]
+    fn rejects_legacy_text_lines() {
+        assert!(decode::<ClientRequest>("bob:hello").is_err());
+    }
+}
@@ -0,0 +1,31 @@
+// Audit log of security relevant events, `<data_dir>/audit.log`.
+//
+// One line per event, the time in seconds since the unix epoch first. The file
+// is only ever appended to, whatever storage backend is in use, so operators can
+// tail and rotate it like any other log.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::storage;
+use crate::Result;
+
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(dir: &Path) -> Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(dir.join("audit.log"))?;
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+
+    //Appends one event, blocking
+    pub fn record(&self, event: &str) -> Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(format!("{} {}\n", storage::now(), event).as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+// Per-user block lists.
+//
+// Owned by the broker like `Rooms`. A block stops direct messages, including
+// queued ones, and room messages from the blocked user reaching the blocker.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::storage::{BlockRecord, Storage};
+use crate::Result;
+
+pub struct Blocks {
+    blocked: HashMap<String, HashSet<String>>, //user -> users they blocked
+}
+
+impl Blocks {
+    pub fn load(storage: &dyn Storage) -> Result<Blocks> {
+        let mut blocks = Blocks {
+            blocked: HashMap::new(),
+        };
+        for record in storage.load_blocks()? {
+            blocks.block(&record.user, &record.blocked);
+        }
+        Ok(blocks)
+    }
+
+    //True if `recipient` does not want messages from `sender`
+    pub fn is_blocked(&self, recipient: &str, sender: &str) -> bool {
+        self.blocked
+            .get(recipient)
+            .is_some_and(|blocked| blocked.contains(sender))
+    }
+
+    //Returns the record to persist, None if the block existed already
+    pub fn block(&mut self, user: &str, other: &str) -> Option<BlockRecord> {
+        let added = self
+            .blocked
+            .entry(user.to_string())
+            .or_default()
+            .insert(other.to_string());
+        added.then(|| record(user, other))
+    }
+
+    //Returns the record to remove, None if there was no such block
+    pub fn unblock(&mut self, user: &str, other: &str) -> Option<BlockRecord> {
+        let blocked = self.blocked.get_mut(user)?;
+        let removed = blocked.remove(other);
+        if blocked.is_empty() {
+            self.blocked.remove(user);
+        }
+        removed.then(|| record(user, other))
+    }
+}
+
+fn record(user: &str, blocked: &str) -> BlockRecord {
+    BlockRecord {
+        user: user.to_string(),
+        blocked: blocked.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_and_unblock() {
+        let mut blocks = Blocks {
+            blocked: HashMap::new(),
+        };
+        assert!(blocks.block("alice", "bob").is_some());
+        assert!(blocks.block("alice", "bob").is_none());
+        assert!(blocks.is_blocked("alice", "bob"));
+        assert!(!blocks.is_blocked("bob", "alice"));
+
+        assert!(blocks.unblock("alice", "bob").is_some());
+        assert!(blocks.unblock("alice", "bob").is_none());
+        assert!(!blocks.is_blocked("alice", "bob"));
+    }
+}
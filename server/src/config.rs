@@ -0,0 +1,529 @@
+// Server configuration.
+//
+// Settings are read from a TOML file (`./server.toml` unless `--config` says
+// otherwise) and can be overridden on the command line. Both sources go through
+// the same setters, and every bad key or value is collected so the operator sees
+// all problems at once instead of fixing them one restart at a time.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chat_common::protocol::MAX_CHUNK_FRAME;
+use log::LevelFilter;
+use toml::Value;
+
+use crate::storage::Backend;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: Vec<SocketAddr>,
+    pub data_dir: PathBuf,
+    pub storage: Backend,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub admins: Vec<String>, //may unlock locked out accounts
+    pub shutdown_timeout_secs: u64, //how long clients get to receive what is queued when the server stops
+    pub login_timeout_secs: u64, //connections that did not log in by then are closed and free their place
+    pub max_login_attempts: u32,
+    pub duplicate_login: DuplicateLogin,
+    pub session_ttl_secs: u64,
+    pub lockout_threshold: u32,    //wrong passwords for one account before it locks
+    pub ip_lockout_threshold: u32, //failed logins from one address before it locks
+    pub lockout_secs: u64,
+    pub login_prompts: bool, //offer the prompt dialog to clients that do not start with a hello
+    pub max_message_bytes: usize,
+    pub peer_queue: usize, //events waiting for one client before `overflow` applies
+    pub overflow: Overflow,
+    pub offline_max_per_user: usize,
+    pub offline_max_age_secs: u64,
+    pub resume_window_secs: u64,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec!["127.0.0.1:8080".parse().unwrap()],
+            data_dir: PathBuf::from("."),
+            storage: Backend::FlatFile,
+            max_connections: 1024,
+            max_connections_per_ip: 16,
+            admins: Vec::new(),
+            shutdown_timeout_secs: 10,
+            login_timeout_secs: 60,
+            max_login_attempts: 3,
+            duplicate_login: DuplicateLogin::KickOld,
+            session_ttl_secs: 30 * 24 * 60 * 60,
+            lockout_threshold: 5,
+            ip_lockout_threshold: 20,
+            lockout_secs: 15 * 60,
+            login_prompts: false,
+            max_message_bytes: 64 * 1024,
+            peer_queue: 1024,
+            overflow: Overflow::Disconnect,
+            offline_max_per_user: 100,
+            offline_max_age_secs: 7 * 24 * 60 * 60,
+            resume_window_secs: 10 * 60,
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+//What happens when an account logs in while it already has a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateLogin {
+    KickOld,   //the old session is logged out
+    RejectNew, //the new login is refused
+    Multi,     //both stay, messages go to every session
+}
+
+impl FromStr for DuplicateLogin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kick_old" => Ok(DuplicateLogin::KickOld),
+            "reject_new" => Ok(DuplicateLogin::RejectNew),
+            "multi" => Ok(DuplicateLogin::Multi),
+            other => Err(format!(
+                "unknown policy `{}`, expected `kick_old`, `reject_new` or `multi`",
+                other
+            )),
+        }
+    }
+}
+
+//What happens when a client falls so far behind that its queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    DropOldest, //the oldest queued event is dropped
+    Disconnect, //the client is disconnected, it can reconnect and catch up
+    Spill,      //events go to a file until the client caught up
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(Overflow::DropOldest),
+            "disconnect" => Ok(Overflow::Disconnect),
+            "spill" => Ok(Overflow::Spill),
+            other => Err(format!(
+                "unknown policy `{}`, expected `drop_oldest`, `disconnect` or `spill`",
+                other
+            )),
+        }
+    }
+}
+
+//One problem found while loading the configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub source: String, //file path or "command line"
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}: {}", self.source, self.message)
+        } else {
+            write!(f, "{}: `{}`: {}", self.source, self.key, self.message)
+        }
+    }
+}
+
+type Setter = fn(&mut Config, &Value) -> Result<(), String>;
+
+//Every known key, its command line flag and how to apply a value
+const SETTINGS: &[(&str, &str, Setter)] = &[
+    ("server.bind", "--bind", |c, v| {
+        c.bind = match v {
+            Value::Array(addrs) if !addrs.is_empty() => {
+                addrs.iter().map(socket_addr).collect::<Result<_, _>>()?
+            }
+            Value::Array(_) => return Err("needs at least one address".to_string()),
+            other => vec![socket_addr(other)?],
+        };
+        Ok(())
+    }),
+    ("server.max_connections", "--max-connections", |c, v| {
+        c.max_connections = positive(v)? as usize;
+        Ok(())
+    }),
+    ("server.max_connections_per_ip", "--max-connections-per-ip", |c, v| {
+        c.max_connections_per_ip = positive(v)? as usize;
+        Ok(())
+    }),
+    //a list in the file, comma separated on the command line
+    ("server.admins", "--admins", |c, v| {
+        let names: Vec<&str> = match v {
+            Value::Array(names) => names.iter().map(string).collect::<Result<_, _>>()?,
+            other => string(other)?.split(',').collect(),
+        };
+        c.admins = names
+            .iter()
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        Ok(())
+    }),
+    ("server.shutdown_timeout_secs", "--shutdown-timeout-secs", |c, v| {
+        c.shutdown_timeout_secs = positive(v)?;
+        Ok(())
+    }),
+    ("server.login_timeout_secs", "--login-timeout-secs", |c, v| {
+        c.login_timeout_secs = positive(v)?;
+        Ok(())
+    }),
+    ("storage.data_dir", "--data-dir", |c, v| {
+        c.data_dir = PathBuf::from(string(v)?);
+        Ok(())
+    }),
+    ("storage.backend", "--storage", |c, v| {
+        c.storage = string(v)?.parse()?;
+        Ok(())
+    }),
+    ("login.max_attempts", "--max-login-attempts", |c, v| {
+        c.max_login_attempts = u32::try_from(positive(v)?).map_err(|e| e.to_string())?;
+        Ok(())
+    }),
+    ("login.duplicate_policy", "--duplicate-policy", |c, v| {
+        c.duplicate_login = string(v)?.parse()?;
+        Ok(())
+    }),
+    ("login.session_ttl_secs", "--session-ttl-secs", |c, v| {
+        c.session_ttl_secs = positive(v)?;
+        Ok(())
+    }),
+    ("login.lockout_threshold", "--lockout-threshold", |c, v| {
+        c.lockout_threshold = u32::try_from(positive(v)?).map_err(|e| e.to_string())?;
+        Ok(())
+    }),
+    ("login.ip_lockout_threshold", "--ip-lockout-threshold", |c, v| {
+        c.ip_lockout_threshold = u32::try_from(positive(v)?).map_err(|e| e.to_string())?;
+        Ok(())
+    }),
+    ("login.lockout_secs", "--lockout-secs", |c, v| {
+        c.lockout_secs = positive(v)?;
+        Ok(())
+    }),
+    ("login.prompts", "--login-prompts", |c, v| {
+        c.login_prompts = boolean(v)?;
+        Ok(())
+    }),
+    //file chunks come in frames of their own, a smaller limit would stall every transfer
+    ("limits.max_message_bytes", "--max-message-bytes", |c, v| {
+        let max = positive(v)? as usize;
+        if max < MAX_CHUNK_FRAME {
+            return Err(format!("must be at least {} bytes so file chunks fit, got {}", MAX_CHUNK_FRAME, max));
+        }
+        c.max_message_bytes = max;
+        Ok(())
+    }),
+    ("limits.peer_queue", "--peer-queue", |c, v| {
+        c.peer_queue = positive(v)? as usize;
+        Ok(())
+    }),
+    ("limits.overflow", "--overflow", |c, v| {
+        c.overflow = string(v)?.parse()?;
+        Ok(())
+    }),
+    ("offline.max_per_user", "--offline-max-per-user", |c, v| {
+        c.offline_max_per_user = positive(v)? as usize;
+        Ok(())
+    }),
+    ("offline.max_age_secs", "--offline-max-age-secs", |c, v| {
+        c.offline_max_age_secs = positive(v)?;
+        Ok(())
+    }),
+    ("files.resume_window_secs", "--resume-window-secs", |c, v| {
+        c.resume_window_secs = positive(v)?;
+        Ok(())
+    }),
+    ("log.level", "--log-level", |c, v| {
+        c.log_level = string(v)?.parse().map_err(|_| {
+            "expected one of `off`, `error`, `warn`, `info`, `debug`, `trace`".to_string()
+        })?;
+        Ok(())
+    }),
+];
+
+fn string(value: &Value) -> Result<&str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("expected a string, got {}", value))
+}
+
+//Command line values arrive as strings
+fn boolean(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        Value::String(s) if s == "true" => Ok(true),
+        Value::String(s) if s == "false" => Ok(false),
+        _ => Err(format!("expected true or false, got {}", value)),
+    }
+}
+
+//Command line values arrive as strings
+fn positive(value: &Value) -> Result<u64, String> {
+    let n = match value {
+        Value::Integer(n) => u64::try_from(*n).ok(),
+        Value::String(s) => s.parse::<u64>().ok(),
+        _ => None,
+    };
+    match n {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(format!("expected a positive integer, got {}", value)),
+    }
+}
+
+fn socket_addr(value: &Value) -> Result<SocketAddr, String> {
+    let addr = string(value)?;
+    addr.parse()
+        .map_err(|_| format!("`{}` is not an address like 127.0.0.1:8080", addr))
+}
+
+impl Config {
+    //Applies a single `section.key` setting
+    fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match SETTINGS.iter().find(|(name, _, _)| *name == key) {
+            Some((_, _, setter)) => setter(self, value),
+            None => Err("unknown key".to_string()),
+        }
+    }
+
+    //Applies every setting of a TOML document, returning all problems found
+    pub fn apply_toml(&mut self, source: &str, text: &str) -> Vec<ConfigError> {
+        let error = |key: &str, message: String| ConfigError {
+            source: source.to_string(),
+            key: key.to_string(),
+            message,
+        };
+
+        let table = match text.parse::<toml::Table>() {
+            Ok(table) => table,
+            Err(e) => return vec![error("", e.to_string())],
+        };
+
+        let mut errors = Vec::new();
+        for (section, entries) in &table {
+            let entries = match entries.as_table() {
+                Some(entries) => entries,
+                None => {
+                    errors.push(error(section, "expected a [section]".to_string()));
+                    continue;
+                }
+            };
+            for (name, value) in entries {
+                let key = format!("{}.{}", section, name);
+                if let Err(message) = self.set(&key, value) {
+                    errors.push(error(&key, message));
+                }
+            }
+        }
+        errors
+    }
+
+    //Applies command line overrides, `--bind` may be repeated.
+    //Returns the path given with `--config`, if any, and every problem found.
+    pub fn apply_args(&mut self, args: &[String]) -> (Option<PathBuf>, Vec<ConfigError>) {
+        let error = |key: &str, message: String| ConfigError {
+            source: "command line".to_string(),
+            key: key.to_string(),
+            message,
+        };
+
+        let mut config_path = None;
+        let mut binds = Vec::new();
+        let mut errors = Vec::new();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => {
+                    errors.push(error(flag, "missing value".to_string()));
+                    break;
+                }
+            };
+            if flag == "--config" {
+                config_path = Some(PathBuf::from(value));
+                continue;
+            }
+            if flag == "--bind" {
+                binds.push(Value::String(value.clone()));
+                continue;
+            }
+            let key = match SETTINGS.iter().find(|(_, f, _)| f == flag) {
+                Some((key, _, _)) => *key,
+                None => {
+                    errors.push(error(flag, "unknown option, see --help".to_string()));
+                    continue;
+                }
+            };
+            //left as text, the setters that want numbers parse it, `--data-dir 2024` stays a path
+            if let Err(message) = self.set(key, &Value::String(value.clone())) {
+                errors.push(error(flag, message));
+            }
+        }
+        if !binds.is_empty() {
+            if let Err(message) = self.set("server.bind", &Value::Array(binds)) {
+                errors.push(error("--bind", message));
+            }
+        }
+        (config_path, errors)
+    }
+
+    //Builds the configuration from the optional file and the command line,
+    //flags win over the file
+    pub fn load(args: &[String]) -> Result<Config, Vec<ConfigError>> {
+        //first pass only to find --config, the flags are applied again after the file
+        let (explicit_path, mut errors) = Config::default().apply_args(args);
+        let path = explicit_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("server.toml"));
+
+        let mut config = Config::default();
+        match std::fs::read_to_string(&path) {
+            Ok(text) => errors.extend(config.apply_toml(&path.display().to_string(), &text)),
+            Err(e) if explicit_path.is_some() || e.kind() != std::io::ErrorKind::NotFound => errors
+                .push(ConfigError {
+                    source: path.display().to_string(),
+                    key: String::new(),
+                    message: e.to_string(),
+                }),
+            Err(_) => (), //no config file, defaults it is
+        }
+        if errors.is_empty() {
+            errors.extend(config.apply_args(args).1);
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+pub fn usage() -> String {
+    let mut text = String::from("Usage: server [--config <file>] [options]\n\nOptions:\n");
+    text.push_str("  --config <file>               configuration file, default ./server.toml\n");
+    for (key, flag, _) in SETTINGS {
+        text.push_str(&format!(
+            "  {:<30}overrides `{}`\n",
+            format!("{} <value>", flag),
+            key
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_bad_key_and_value() {
+        let mut config = Config::default();
+        let errors = config.apply_toml(
+            "server.toml",
+            r#"
+            [server]
+            bind = ["0.0.0.0:9000", "nonsense"]
+            max_connections = 0
+            colour = "blue"
+
+            [login]
+            max_attempts = 5
+
+            [log]
+            level = "loud"
+            "#,
+        );
+        let keys: Vec<&str> = errors.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "log.level",
+                "server.bind",
+                "server.colour",
+                "server.max_connections"
+            ]
+        );
+        assert_eq!(config.max_login_attempts, 5);
+    }
+
+    #[test]
+    fn message_limit_leaves_room_for_file_chunks() {
+        let mut config = Config::default();
+        let errors = config.apply_toml("server.toml", "[limits]\nmax_message_bytes = 4096\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, "limits.max_message_bytes");
+        assert!(errors[0].message.contains("file chunks"));
+        assert_eq!(config.max_message_bytes, 64 * 1024);
+
+        let limit = MAX_CHUNK_FRAME.to_string();
+        let (_, errors) = config.apply_args(&["--max-message-bytes".to_string(), limit]);
+        assert!(errors.is_empty());
+        assert_eq!(config.max_message_bytes, MAX_CHUNK_FRAME);
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let mut config = Config::default();
+        assert!(config
+            .apply_toml("server.toml", "[server]\nmax_connections = 10\n")
+            .is_empty());
+        let args: Vec<String> = [
+            "--max-connections",
+            "20",
+            "--bind",
+            "127.0.0.1:1",
+            "--bind",
+            "[::1]:2",
+            "--duplicate-policy",
+            "multi",
+            "--login-prompts",
+            "true",
+            "--admins",
+            "Alice, bob",
+            "--overflow",
+            "spill",
+            "--max-connections-per-ip",
+            "4",
+            "--login-timeout-secs",
+            "5",
+            "--data-dir",
+            "2024",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let (path, errors) = config.apply_args(&args);
+        assert!(path.is_none() && errors.is_empty());
+        assert_eq!(config.max_connections, 20);
+        assert_eq!(config.bind.len(), 2);
+        assert_eq!(config.duplicate_login, DuplicateLogin::Multi);
+        assert!(config.login_prompts);
+        assert_eq!(config.admins, vec!["alice", "bob"]);
+        assert_eq!(config.overflow, Overflow::Spill);
+        assert_eq!(config.max_connections_per_ip, 4);
+        assert_eq!(config.login_timeout_secs, 5);
+        assert_eq!(config.data_dir, PathBuf::from("2024"));
+
+        //names and paths that look like numbers are still names and paths
+        let (_, errors) = config.apply_args(&["--admins".to_string(), "42".to_string()]);
+        assert!(errors.is_empty());
+        assert_eq!(config.admins, vec!["42"]);
+        let (_, errors) = config.apply_args(&["--max-connections".to_string(), "-1".to_string()]);
+        assert_eq!(errors.len(), 1);
+
+        let (_, errors) = config.apply_args(&["--frobnicate".to_string(), "1".to_string()]);
+        assert_eq!(errors.len(), 1);
+    }
+}
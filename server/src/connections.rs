@@ -0,0 +1,115 @@
+// Open connection counts, in total and per source address.
+//
+// The accept loop admits a socket only while both are under their limits, the
+// guard it gets back holds the connection's place until the connection task
+// ends, logged in or not. Shared with the broker, which shows the counts to
+// admins.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chat_common::protocol::{AddressInfo, ConnectionInfo};
+
+pub struct Connections {
+    max: usize,
+    max_per_address: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    open: usize,
+    addresses: HashMap<IpAddr, usize>, //only addresses with a connection open
+}
+
+//Why a connection was not admitted
+#[derive(Debug, PartialEq)]
+pub enum Refused {
+    Full,
+    TooManyFromAddress,
+}
+
+//Counts a connection as open until dropped
+pub struct ConnectionGuard {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+}
+
+impl Connections {
+    pub fn new(max: usize, max_per_address: usize) -> Connections {
+        Connections {
+            max,
+            max_per_address,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //Counts a new connection from `ip` unless a limit is reached
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionGuard, Refused> {
+        let mut state = self.lock();
+        if state.open >= self.max {
+            return Err(Refused::Full);
+        }
+        let from_address = state.addresses.entry(ip).or_default();
+        if *from_address >= self.max_per_address {
+            return Err(Refused::TooManyFromAddress);
+        }
+        *from_address += 1;
+        state.open += 1;
+        Ok(ConnectionGuard { connections: Arc::clone(self), ip })
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        let state = self.lock();
+        let mut addresses: Vec<AddressInfo> = state.addresses.iter()
+            .map(|(ip, connections)| AddressInfo { address: ip.to_string(), connections: *connections })
+            .collect();
+        addresses.sort_by(|a, b| b.connections.cmp(&a.connections).then_with(|| a.address.cmp(&b.address)));
+        ConnectionInfo { open: state.open, max: self.max, max_per_address: self.max_per_address, addresses }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.connections.lock();
+        state.open -= 1;
+        if let Some(from_address) = state.addresses.get_mut(&self.ip) {
+            *from_address -= 1;
+            if *from_address == 0 {
+                state.addresses.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_in_total_and_per_address() {
+        let connections = Arc::new(Connections::new(3, 2));
+        let one: IpAddr = "10.0.0.1".parse().unwrap();
+        let two: IpAddr = "10.0.0.2".parse().unwrap();
+        let first = connections.admit(one).unwrap();
+        let _second = connections.admit(one).unwrap();
+        assert_eq!(connections.admit(one).err(), Some(Refused::TooManyFromAddress));
+        let _third = connections.admit(two).unwrap();
+        assert_eq!(connections.admit(two).err(), Some(Refused::Full));
+
+        let info = connections.info();
+        assert_eq!(info.open, 3);
+        let addresses: Vec<(&str, usize)> = info.addresses.iter().map(|a| (a.address.as_str(), a.connections)).collect();
+        assert_eq!(addresses, vec![("10.0.0.1", 2), ("10.0.0.2", 1)]);
+
+        //a closed connection makes room again
+        drop(first);
+        let _again = connections.admit(one).unwrap();
+        assert_eq!(connections.info().open, 3);
+    }
+}
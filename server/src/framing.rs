@@ -0,0 +1,80 @@
+// Line reader with an upper bound on the line length.
+//
+// `BufRead::lines` keeps growing its buffer until it sees a newline, so a client
+// that never sends one could make the server allocate without limit. This reader
+// stops buffering at `max` bytes and skips the rest of the line instead.
+
+use async_std::io;
+use futures::io::{AsyncBufRead, AsyncBufReadExt};
+
+pub enum Frame {
+    Line(String),
+    TooLong(usize), //length of the skipped line
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    max: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, max: usize) -> Self {
+        FrameReader { reader, max }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    //Next line without its line ending, None once the peer closed the connection
+    pub async fn next(&mut self) -> Option<io::Result<Frame>> {
+        let mut line = Vec::new();
+        let mut len = 0;
+        let mut too_long = false;
+        loop {
+            let available = match self.reader.fill_buf().await {
+                Ok(available) => available,
+                Err(e) => return Some(Err(e)),
+            };
+            if available.is_empty() {
+                if len == 0 {
+                    return None;
+                }
+                break; //last line without a newline
+            }
+
+            let (used, done) = match available.iter().position(|&b| b == b'\n') {
+                Some(idx) => (idx + 1, true),
+                None => (available.len(), false),
+            };
+            len += used;
+            if !too_long {
+                if len > self.max + 2 {
+                    too_long = true;
+                    line = Vec::new();
+                } else {
+                    line.extend_from_slice(&available[..used]);
+                }
+            }
+            self.reader.consume_unpin(used);
+            if done {
+                break;
+            }
+        }
+
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        if too_long || line.len() > self.max {
+            return Some(Ok(Frame::TooLong(len)));
+        }
+        Some(
+            String::from_utf8(line)
+                .map(Frame::Line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        )
+    }
+}
@@ -0,0 +1,258 @@
+// Failed login tracking per account and per source address.
+//
+// A wrong password counts against the account and the address it came from, an
+// unknown username only against the address. The answer to a failed login is
+// held back, twice as long for every failure in a row, and an account or
+// address over its threshold is locked: no password is checked for it until the
+// lock runs out or an admin lifts it. Failures are forgotten after a lock period
+// without any. Every lock and unlock goes to the audit log.
+//
+// Counters are only kept in memory, a restart clears them. Shared by the
+// connection tasks like `Tokens`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use async_std::task;
+use log::{error, warn};
+
+use crate::audit::AuditLog;
+
+const FIRST_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+pub struct Lockout {
+    audit: Arc<AuditLog>,
+    state: Mutex<State>,
+}
+
+struct State {
+    account_threshold: u32,
+    ip_threshold: u32,
+    lock_for: Duration,
+    accounts: HashMap<String, Failures>,
+    ips: HashMap<IpAddr, Failures>,
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32, //in a row, since the last lock
+    last: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn locked(&self, now: Instant) -> Option<Duration> {
+        self.locked_until.filter(|until| *until > now).map(|until| until - now)
+    }
+
+    //Counts one failure, returns the count and whether it locked
+    fn fail(&mut self, threshold: u32, lock_for: Duration, now: Instant) -> (u32, bool) {
+        if self.last.is_some_and(|last| now.duration_since(last) > lock_for) {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = Some(now);
+        let count = self.count;
+        if count < threshold {
+            return (count, false);
+        }
+        self.count = 0;
+        self.locked_until = Some(now + lock_for);
+        (count, true)
+    }
+
+    //Nothing worth keeping, no lock and the failures are forgotten
+    fn stale(&self, lock_for: Duration, now: Instant) -> bool {
+        self.locked(now).is_none() && self.last.map_or(true, |last| now.duration_since(last) > lock_for)
+    }
+}
+
+impl State {
+    fn locked(&self, name: &str, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let account = self.accounts.get(name).and_then(|failures| failures.locked(now));
+        let address = self.ips.get(&ip).and_then(|failures| failures.locked(now));
+        account.max(address)
+    }
+
+    //Counts a failed login, returns how long to hold the answer back and what got locked
+    fn failed(&mut self, name: Option<&str>, ip: IpAddr, now: Instant) -> (Duration, Vec<String>) {
+        let lock_for = self.lock_for;
+        self.accounts.retain(|_, failures| !failures.stale(lock_for, now));
+        self.ips.retain(|_, failures| !failures.stale(lock_for, now));
+
+        let mut locks = Vec::new();
+        let (mut count, locked) = self.ips.entry(ip).or_default().fail(self.ip_threshold, lock_for, now);
+        if locked {
+            locks.push(format!("lock address={} failures={} secs={}", ip, count, lock_for.as_secs()));
+        }
+        if let Some(name) = name {
+            let (failures, locked) = self.accounts.entry(name.to_string()).or_default().fail(self.account_threshold, lock_for, now);
+            if locked {
+                locks.push(format!("lock account={} address={} failures={} secs={}", name, ip, failures, lock_for.as_secs()));
+            }
+            count = count.max(failures);
+        }
+        (delay(count), locks)
+    }
+}
+
+//Doubles with every failure in a row, the first one waits FIRST_DELAY
+fn delay(failures: u32) -> Duration {
+    FIRST_DELAY.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(MAX_DELAY)
+}
+
+//Lifts the lock of one entry, true if there was one
+fn lift<K: Hash + Eq>(map: &mut HashMap<K, Failures>, key: &K, now: Instant) -> bool {
+    map.remove(key).is_some_and(|failures| failures.locked(now).is_some())
+}
+
+impl Lockout {
+    pub fn new(audit: Arc<AuditLog>, account_threshold: u32, ip_threshold: u32, lock_for: Duration) -> Lockout {
+        Lockout {
+            audit,
+            state: Mutex::new(State {
+                account_threshold,
+                ip_threshold,
+                lock_for,
+                accounts: HashMap::new(),
+                ips: HashMap::new(),
+            }),
+        }
+    }
+
+    //How long until a login for `name` from `ip` is checked again, None if it is not locked
+    pub async fn locked(&self, name: &str, ip: IpAddr) -> Option<Duration> {
+        self.state.lock().await.locked(name, ip, Instant::now())
+    }
+
+    //Counts a wrong password for `name`, or an unknown username without one.
+    //Returns how long to wait before answering.
+    pub async fn failed(&self, name: Option<&str>, ip: IpAddr) -> Duration {
+        let (delay, locks) = self.state.lock().await.failed(name, ip, Instant::now());
+        for lock in locks {
+            warn!("Login {}", lock);
+            self.record(lock).await;
+        }
+        delay
+    }
+
+    //A good password starts the account's count over, the address keeps its own
+    pub async fn succeeded(&self, name: &str) {
+        self.state.lock().await.accounts.remove(name);
+    }
+
+    //Lifts the lock of an account, or of an address if `target` is one.
+    //False if it was not locked.
+    pub async fn unlock(&self, target: &str, by: &str) -> bool {
+        let now = Instant::now();
+        let (unlocked, event) = {
+            let mut state = self.state.lock().await;
+            match target.parse::<IpAddr>() {
+                Ok(ip) => (lift(&mut state.ips, &ip, now), format!("unlock address={} by={}", ip, by)),
+                Err(_) => (lift(&mut state.accounts, &target.to_string(), now), format!("unlock account={} by={}", target, by)),
+            }
+        };
+        if unlocked {
+            self.record(event).await;
+        }
+        unlocked
+    }
+
+    async fn record(&self, event: String) {
+        let audit = Arc::clone(&self.audit);
+        if let Err(e) = task::spawn_blocking(move || audit.record(&event)).await {
+            error!("Failed to write the audit log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        State {
+            account_threshold: 3,
+            ip_threshold: 5,
+            lock_for: Duration::from_secs(60),
+            accounts: HashMap::new(),
+            ips: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn delays_double_and_accounts_lock() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        let mut state = state();
+        assert_eq!(state.failed(Some("bob"), ip, now), (FIRST_DELAY, vec![]));
+        assert_eq!(state.failed(Some("bob"), ip, now).0, FIRST_DELAY * 2);
+        assert!(state.locked("bob", ip, now).is_none());
+
+        let (wait, locks) = state.failed(Some("bob"), ip, now);
+        assert_eq!(wait, FIRST_DELAY * 4);
+        assert_eq!(locks, vec!["lock account=bob address=10.0.0.1 failures=3 secs=60"]);
+        //from anywhere, but only that account
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(state.locked("bob", other, now), Some(Duration::from_secs(60)));
+        assert!(state.locked("alice", other, now).is_none());
+        assert!(state.locked("bob", other, now + Duration::from_secs(61)).is_none());
+        assert_eq!(delay(100), MAX_DELAY);
+    }
+
+    #[test]
+    fn addresses_lock_and_failures_are_forgotten() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        let mut state = state();
+        state.failed(Some("bob"), ip, now);
+        state.failed(Some("bob"), ip, now);
+        //a quiet period starts the count over
+        let later = now + Duration::from_secs(120);
+        state.failed(Some("bob"), ip, later);
+        assert!(state.locked("bob", ip, later).is_none());
+
+        //guessing names locks the address for every account, the wrong password above counts
+        for _ in 0..3 {
+            assert!(state.failed(None, ip, later).1.is_empty());
+        }
+        assert_eq!(state.failed(None, ip, later).1, vec!["lock address=10.0.0.1 failures=5 secs=60"]);
+        assert!(state.locked("alice", ip, later).is_some());
+        assert!(state.locked("alice", "10.0.0.2".parse().unwrap(), later).is_none());
+    }
+
+    #[test]
+    fn admins_unlock_and_it_is_audited() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = Arc::new(AuditLog::open(dir.path()).unwrap());
+        let lockout = Lockout::new(audit, 1, 1, Duration::from_secs(60));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        task::block_on(async {
+            lockout.failed(Some("bob"), ip).await;
+            assert!(lockout.locked("bob", ip).await.is_some());
+            assert!(lockout.unlock("bob", "admin").await);
+            assert!(!lockout.unlock("bob", "admin").await);
+            //the address is still locked
+            assert!(lockout.locked("bob", ip).await.is_some());
+            assert!(lockout.unlock("10.0.0.1", "admin").await);
+            assert!(lockout.locked("bob", ip).await.is_none());
+        });
+
+        let log = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        let events: Vec<&str> = log.lines().map(|line| line.split_once(' ').unwrap().1).collect();
+        assert_eq!(
+            events,
+            vec![
+                "lock address=10.0.0.1 failures=1 secs=60",
+                "lock account=bob address=10.0.0.1 failures=1 secs=60",
+                "unlock account=bob by=admin",
+                "unlock address=10.0.0.1 by=admin",
+            ]
+        );
+    }
+}
@@ -7,97 +7,261 @@
 // - Do you want/need some form of user management? If so, how would that look like?
 
 
-use std::fs::File;
-use std::io::prelude::*;
-use std::fs::OpenOptions;
-use std::io::{self, BufRead};
-use std::io::Write;
-use std::path::Path;
-use std::{thread, time::{self, Duration}};
-use std::sync::Mutex;
-
-use async_std::fs::read_to_string;
+mod audit;
+mod blocks;
+mod config;
+mod connections;
+mod framing;
+mod lockout;
+mod offline;
+mod password;
+mod queue;
+mod rooms;
+mod storage;
+mod tokens;
+mod transfers;
+mod users;
+
 use async_std::net::TcpStream;
 
-use futures::channel::mpsc;
+use chat_common::protocol::{self, ClientRequest, DeliveryStatus, ErrorKind, HistoryMessage, LoginFailure, QueueInfo, ServerEvent, SessionInfo, SystemNotice};
+use futures::channel::{mpsc, oneshot};
 use futures::select;
 use futures::FutureExt;
 use futures::sink::SinkExt;
+use log::{debug, error, info, warn};
+use std::any::Any;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::collections::hash_map::{Entry, HashMap};
+use std::time::Duration;
+use std::collections::hash_map::HashMap;
+use audit::AuditLog;
+use blocks::Blocks;
+use config::{Config, DuplicateLogin};
+use connections::{Connections, Refused};
+use framing::{Frame, FrameReader};
+use lockout::Lockout;
+use offline::OfflineQueue;
+use queue::{PeerReceiver, PeerSender, Queues};
+use rooms::{RoomOp, Rooms};
+use storage::{RoomRecord, SessionRecord, Storage, StoredMessage};
+use tokens::Tokens;
+use transfers::{Offer, Resumed, Started, TransferError, Transfers};
+use users::{RegisterError, UserStore};
 
 // Boiler plate
 use async_std::{
+    future,
     io::BufReader,  
     prelude::*,
     task, 
-    net::{TcpListener, ToSocketAddrs}, 
+    net::TcpListener, 
 };
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
-type Sender<T> = mpsc::UnboundedSender<T>;
-type Receiver<T> = mpsc::UnboundedReceiver<T>;
+type Sender<T> = mpsc::Sender<T>;
+type Receiver<T> = mpsc::Receiver<T>;
+
+const HISTORY_LIMIT: usize = 200; //most room messages sent for one history request
+const BROKER_QUEUE: usize = 1024; //events waiting for the broker before connections have to wait
+const BROKER_RESTART_DELAY: Duration = Duration::from_secs(1); //after the broker failed, so a broken storage does not spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100); //after a failed accept, out of file descriptors usually lasts a moment
 
 //Event Queue
 enum Event { // 1
     NewPeer {
         name: String,
-        stream: Arc<TcpStream>,
-        shutdown: Receiver<Void>,
+        id: u64, //of the connection
+        session: SessionRecord, //the login session the connection uses
+        token: Option<String>, //handed to the client when the login started the session
+        addr: String,
+        sender: PeerSender,
+        accepted: oneshot::Sender<std::result::Result<(), ServerEvent>>, //what to tell the client if the session was refused
+    },
+    //A logged in connection ended, with what its writer did not send
+    Disconnect {
+        name: String,
+        id: u64,
+        pending: PeerReceiver,
     },
     Message {
         from: String,
         to: Vec<String>,
         msg: String,
+        id: Option<u64>,
+    },
+    FileStart {
+        from: String,
+        transfer: String,
+        to: Vec<String>,
+        filename: String,
+        size: u64,
+        sha256: String,
+        id: Option<u64>,
+    },
+    FileChunk {
+        from: String,
+        transfer: String,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    FileResume {
+        from: String,
+        sender: String,
+        transfer: String,
+        offset: u64,
+    },
+    FileAck {
+        from: String,
+        sender: String,
+        transfer: String,
+        offset: u64,
+    },
+    FileReject {
+        from: String,
+        sender: String,
+        transfer: String,
+    },
+    Room {
+        from: String,
+        op: RoomOp,
+    },
+    Block {
+        from: String,
+        user: String,
+        block: bool, //false to unblock
+    },
+    Sessions {
+        from: String,
+        current: String, //id of the session the asking connection logged in with
+        revoke: Option<String>, //None only lists them
+    },
+    Unlock {
+        from: String,
+        target: String,
+    },
+    Stats {
+        from: String,
+    },
+    //The server stops: no more logins, everyone is told and closed once their queues are written
+    Shutdown,
+    //The connections had their time, what they did not write is kept for their next login
+    ShutdownNow {
+        done: oneshot::Sender<()>,
     },
-    SysMessage {
-        stream: Arc<TcpStream>,
-        msg: String
-    }
 }
 
-fn load_userlist() -> File{
-    let user_file = OpenOptions::new()
-    .append(true)
-    .create(true)
-    .read(true)
-    .open("./userlist.txt")
-    .expect("Failed to open or create the file"); //Unrecoverable
+//One logged in connection, an account has several with the `multi` duplicate login policy
+struct Session {
+    id: u64,
+    login: String, //id of the login session in `tokens`, revoking it closes the connection
+    sender: PeerSender,
+}
 
-    user_file
+//The broker letting go of a session ends its connection, the writer flushes what is queued and hangs up.
+//Even if the broker panics, every connection it knew is closed when the unwinding drops the peers.
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.sender.close();
+    }
 }
 
-enum Void {} //Enforcer to ensure messages are sent down an uninhabited  channel
+//Never holds an empty list, an account without sessions is offline
+type Peers = HashMap<String, Vec<Session>>;
 
 //Accept loop for incoming connections
-async fn accept_loop(addr: impl ToSocketAddrs) -> Result<()> {
+async fn accept_loop(config: Arc<Config>, users: Arc<UserStore>, tokens: Arc<Tokens>, lockout: Arc<Lockout>, storage: Arc<dyn Storage>, mut stop: mpsc::UnboundedReceiver<()>) -> Result<()> {
 
-    //Opens user list
-    // let mut user_file = load_userlist();
-
-
-    //binds listener to address
-    let listener = TcpListener::bind(addr).await?;
+    //binds a listener to every configured address
+    let mut listeners = Vec::new();
+    for addr in &config.bind {
+        listeners.push(TcpListener::bind(addr).await?);
+        info!("Listening on {}", addr);
+    }
 
     //create broker to handle events
-    let (broker_sender, broker_receiver) = mpsc::unbounded(); 
-    let _broker_handle = task::spawn(broker_loop(broker_receiver)); 
+    let (mut broker_sender, broker_receiver) = mpsc::channel(BROKER_QUEUE);
+    let connections = Arc::new(Connections::new(config.max_connections, config.max_connections_per_ip));
+    let mut broker_handle = task::spawn(supervise_broker(broker_receiver, storage, Arc::clone(&users), Arc::clone(&tokens), Arc::clone(&lockout), Arc::clone(&connections), Arc::clone(&config)));
+    let queues = Arc::new(Queues::new(config.peer_queue, config.overflow, &config.data_dir)?);
 
-    //handle listener
-    let mut incoming = listener.incoming();
-    while let Some(stream) = incoming.next().await {
-        let stream = stream?;
+    //handle listeners
+    let mut incoming = futures::stream::select_all(listeners.iter().map(|listener| listener.incoming()));
+    loop {
+        let stream = select! {
+            stream = incoming.next().fuse() => match stream {
+                Some(Ok(stream)) => stream,
+                //too many open files or a client that gave up, the listener is fine
+                Some(Err(e)) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    task::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+                None => break,
+            },
+            _ = stop.next().fuse() => break,
+        };
+
+        //the client may be gone already, that is no reason to stop accepting
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                debug!("Dropped a connection without an address: {}", e);
+                continue;
+            }
+        };
+        let guard = match connections.admit(addr.ip()) {
+            Ok(guard) => guard,
+            Err(refused) => {
+                let event = match refused {
+                    Refused::Full => {
+                        warn!("Rejecting {}, already at {} connections", addr, config.max_connections);
+                        ServerEvent::error(ErrorKind::ServerFull, "Server is full, try again later")
+                    }
+                    Refused::TooManyFromAddress => {
+                        warn!("Rejecting {}, already at {} connections from {}", addr, config.max_connections_per_ip, addr.ip());
+                        let message = format!("Too many connections from your address, at most {} are allowed", config.max_connections_per_ip);
+                        ServerEvent::error(ErrorKind::TooManyConnections, message)
+                    }
+                };
+                task::spawn(async move {
+                    if let Ok(line) = protocol::encode(&event) {
+                        let _ = (&stream).write_all(line.as_bytes()).await;
+                    }
+                });
+                continue;
+            }
+        };
 
         //Connected
-        println!("Accepting from  : {}", stream.peer_addr()?);
-        spawn_and_log_error(connection_loop(broker_sender.clone(), stream,));
+        info!("Accepting from {}", addr);
+        let connection = connection_loop(broker_sender.clone(), stream, Arc::clone(&users), Arc::clone(&tokens), Arc::clone(&lockout), Arc::clone(&queues), Arc::clone(&config));
+        spawn_and_log_error(async move {
+            let _guard = guard;
+            connection.await
+        });
     }
-    drop(broker_sender);    //closes broker so that channel is empty
-    match _broker_handle.await{  //Joins broker, ensuring complition ##ASK
-        Ok(()) => Ok(()),
-        Err(e) => { eprintln!("{}",e);
-                    panic!()},
+
+    //no new connections, the logged in ones get until the timeout to receive what is queued for them
+    drop(incoming);
+    drop(listeners);
+    info!("Shutting down");
+    //a broker that is gone already has nothing left to drain
+    if broker_sender.send(Event::Shutdown).await.is_ok() {
+        let grace = Duration::from_secs(config.shutdown_timeout_secs);
+        if future::timeout(grace, &mut broker_handle).await.is_err() {
+            //the broker keeps what the stuck connections did not write for their next login,
+            //unless it finished right after the timeout and drained everything itself
+            let (done, finished) = oneshot::channel();
+            if broker_sender.send(Event::ShutdownNow { done }).await.is_ok() {
+                let _ = finished.await;
+            }
+        }
     }
+    info!("Shut down");
+    Ok(())
 }
 
 //Helper function for error handling
@@ -108,304 +272,1589 @@ where
     task::spawn(async move {
         if let Err(e) = fut.await {
             //logs error
-            eprintln!("{}", e)
+            warn!("{}", e)
         }
     })
 }
 
 
-fn find_user_login(username: String) -> (String,String){
-    let mut user_file = load_userlist();
-    
-    let mut file_content = String::new();
-    user_file.read_to_string(&mut file_content).unwrap();
+type Lines<'a> = FrameReader<BufReader<&'a TcpStream>>;
 
-    for line in file_content.lines(){
-        let (name, pwd) = match line.find(':') { //splits message between destionation and message
-            None => ("",""),
-            Some(idx) => (&line[..idx], line[idx + 1 ..].trim()),
+//Reads the next frame before login. With `prompts` a line that is not JSON is
+//text typed by a telnet user, an answer to the last prompt.
+async fn read_login(lines: &mut Lines<'_>, sender: &PeerSender, prompts: bool) -> Result<ClientRequest> {
+    loop {
+        let line = match lines.next().await {
+            None => Err("peer disconnected immediately")?,
+            Some(frame) => match frame? {
+                Frame::Line(line) => line,
+                Frame::TooLong(len) => {
+                    let _ = sender.push(too_large(len, lines.max()));
+                    continue;
+                }
+            },
         };
-        // println!("{}:{}",name,pwd);
-        if name.eq(&username){
-            return (name.to_string(),pwd.to_string());
+        match protocol::decode::<ClientRequest>(&line) {
+            Ok(request) => return Ok(request),
+            Err(_) if prompts => return Ok(ClientRequest::Input { text: line }),
+            Err(e) => {
+                let _ = sender.push(ServerEvent::error(ErrorKind::Malformed, e.to_string()));
+            }
         }
     }
-
-    return ("".to_string(),"".to_string());
 }
 
-fn register(name: String, pwd: String){
-    static  MY_MUTEX: std::sync::Mutex<i32> = Mutex::new(5);
-    println!("{:?}", MY_MUTEX);
-    
-    
-    let mutlock = MY_MUTEX.lock().unwrap();
-    {
-    
-        thread::sleep(time::Duration::from_millis(10));
-        println!("{:?}", MY_MUTEX);
-        // println!("{:?}", mutex_changer);
-        
-
-        let mut user_file = load_userlist();
-
-        let _ = user_file.write_all(format!("{}:{}\n", name, pwd).as_bytes());
+//Reads the next answer of the prompt dialog, reporting any frame that is not one
+async fn read_input(lines: &mut Lines<'_>, sender: &PeerSender) -> Result<String> {
+    loop {
+        let event = match read_login(lines, sender, true).await? {
+            ClientRequest::Input { text } => return Ok(text),
+            _ => ServerEvent::error(ErrorKind::Unexpected, "Please answer the prompt first"),
+        };
+        let _ = sender.push(event);
     }
 }
 
+//Logs in with `Login`, `Register` or `ResumeSession` after the hello, the session
+//is set when a token was used. None once the client ran out of attempts.
+async fn handshake(lines: &mut Lines<'_>, sender: &PeerSender, ip: IpAddr, users: &UserStore, tokens: &Tokens, lockout: &Lockout, config: &Config) -> Result<Option<(String, Option<SessionRecord>)>> {
+    let mut attempts_left = config.max_login_attempts;
+    loop {
+        let mut delay = Duration::ZERO;
+        let mut retry_after = None;
+        let reason = match read_login(lines, sender, false).await? {
+            ClientRequest::ResumeSession { token } => match tokens.authenticate(&token).await {
+                Some(session) => {
+                    info!("{} logged in with session {}", session.user, session.id);
+                    return Ok(Some((session.user.clone(), Some(session))));
+                }
+                None => LoginFailure::InvalidToken,
+            },
+            ClientRequest::Login { name, password } => {
+                let name = name.trim().to_ascii_lowercase();
+                if let Some(wait) = lockout.locked(&name, ip).await {
+                    retry_after = Some(wait.as_secs() + 1);
+                    LoginFailure::Locked
+                } else {
+                    match users.password_hash(&name).await {
+                        Some(hash) => {
+                            if task::spawn_blocking(move || password::verify(&password, &hash)).await {
+                                info!("{} logged in", name);
+                                lockout.succeeded(&name).await;
+                                return Ok(Some((name, None)));
+                            }
+                            delay = lockout.failed(Some(&name), ip).await;
+                            LoginFailure::WrongPassword
+                        }
+                        None => {
+                            delay = lockout.failed(None, ip).await;
+                            LoginFailure::UnknownUser
+                        }
+                    }
+                }
+            }
+            ClientRequest::Register { name, password } => {
+                let name = name.trim().to_ascii_lowercase();
+                if name.is_empty() || !name.chars().all(char::is_alphanumeric) {
+                    LoginFailure::InvalidName
+                } else if users.contains(&name).await {
+                    LoginFailure::UsernameTaken
+                } else {
+                    let pwd_hash = task::spawn_blocking(move || password::hash(&password)).await?;
+                    match users.register(&name, &pwd_hash).await {
+                        Ok(()) => {
+                            info!("{} registered", name);
+                            return Ok(Some((name, None)));
+                        }
+                        Err(RegisterError::Taken) => LoginFailure::UsernameTaken,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            _ => {
+                let _ = sender.push(ServerEvent::error(ErrorKind::Unexpected, "Please log in first"));
+                continue;
+            }
+        };
+        //guessing names and passwords is what the attempts limit is for
+        if let LoginFailure::UnknownUser | LoginFailure::WrongPassword | LoginFailure::Locked = reason {
+            attempts_left = attempts_left.saturating_sub(1);
+        }
+        let reason = if attempts_left == 0 { LoginFailure::TooManyAttempts } else { reason };
+        task::sleep(delay).await;
+        let _ = sender.push(ServerEvent::LoginFailed { reason, attempts_left, retry_after });
+        if attempts_left == 0 {
+            return Ok(None);
+        }
+    }
+}
 
-async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream) -> Result<()> {
-
-    let stream = Arc::new(stream);
-    let reader = BufReader::new(&*stream);
+fn locked_notice(wait: Duration) -> ServerEvent {
+    ServerEvent::info(format!("Too many failed logins, try again in {} seconds", wait.as_secs() + 1))
+}
 
-    
-    let mut lines = reader.lines();
+//The prompt dialog for raw telnet users, `first` answers whether they have an account.
+//None once the user ran out of password attempts.
+async fn prompt_login(first: String, lines: &mut Lines<'_>, sender: &PeerSender, ip: IpAddr, users: &UserStore, lockout: &Lockout, config: &Config) -> Result<Option<String>> {
     let mut name = "".to_string();
-
-    broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Do you have an account? Y/N".to_string()) }).await?;
+    let mut answer = Some(first);
 
     loop{
-        let mut logged_in = false;
-        let choice = (match lines.next().await {
-            None => Err("peer disconnected immediately")?,
-            Some(line) => line?,
-        }).trim().to_ascii_lowercase().to_string();
+        let choice = match answer.take() {
+            Some(first) => first,
+            None => read_input(lines, sender).await?,
+        }.trim().to_ascii_lowercase();
         
 
         match choice.chars().next() {
             Some('y') => {
                 loop {
-                    let mut logged_in = false;
-                    broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Please enter your username".to_string()) }).await?;
-                    name = (match lines.next().await { 
-                                None => Err("peer disconnected immediately")?,
-                                Some(line) => line?,
-                            }).trim().to_ascii_lowercase().to_string();
-                    // search for user
-                    let (username, userpwd) = find_user_login(name.clone());
-                    // println!("{}->{}",name.clone(),username);
-                    // println!("TEST");
-
-                    if username.eq(""){
-                        broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Incorrect username".to_string()) }).await?;
-                        continue;
+                    let _ = sender.push(ServerEvent::prompt("Please enter your username"));
+                    name = read_input(lines, sender).await?.trim().to_ascii_lowercase();
+                    if let Some(wait) = lockout.locked(&name, ip).await {
+                        let _ = sender.push(locked_notice(wait));
+                        return Ok(None);
                     }
+                    // search for user
+                    let userpwd = match users.password_hash(&name).await {
+                        Some(userpwd) => userpwd,
+                        None => {
+                            task::sleep(lockout.failed(None, ip).await).await;
+                            let _ = sender.push(ServerEvent::info("Incorrect username"));
+                            continue;
+                        }
+                    };
                     
                     
-                    for i in (1..4).rev(){
-                        broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Please enter your password\n\rAttempts remaining ".to_string()+&i.to_string()) }).await?;
+                    for i in (1..=config.max_login_attempts).rev(){
+                        //another connection may have locked the account meanwhile, or our own guesses did
+                        if let Some(wait) = lockout.locked(&name, ip).await {
+                            let _ = sender.push(locked_notice(wait));
+                            return Ok(None);
+                        }
+                        let _ = sender.push(ServerEvent::prompt(format!("Please enter your password\nAttempts remaining {}", i)));
 
-                        let pwd = (match lines.next().await { 
-                            None => Err("peer disconnected immediately")?,
-                            Some(line) => line?,
-                        }).trim().to_string();
+                        let pwd = read_input(lines, sender).await?.trim().to_string();
                         
                         // println!("PASSWORD {}->{}",pwd.len(),userpwd.len());
-                        if !userpwd.eq(&pwd.clone()){
-                            broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Incorrect password".to_string()) }).await?;
+                        let userpwd = userpwd.clone();
+                        if !task::spawn_blocking(move || password::verify(&pwd, &userpwd)).await{
+                            task::sleep(lockout.failed(Some(&name), ip).await).await;
+                            let _ = sender.push(ServerEvent::info("Incorrect password"));
                             continue;
                         }
                         else{
-                            println!("LOGGED IN");
-                            logged_in = true;
-                            break;
+                            info!("{} logged in", name);
+                            lockout.succeeded(&name).await;
+                            return Ok(Some(name));
                         }
                     }
 
-                    if logged_in{
-                        break;
-                    }
+                    //out of attempts, asking for the username again would let guessing go on forever
+                    let _ = sender.push(ServerEvent::info("Too many failed attempts"));
+                    return Ok(None);
                 }
             },
             Some('n') => {
-                broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Please enter your username".to_string()) }).await?;                
-                
-                loop{
-                    name = (match lines.next().await { 
-                        None => Err("peer disconnected immediately")?,
-                        Some(line) => line?,
-                    }).trim().to_ascii_lowercase().to_string();
+                loop {
+                    let _ = sender.push(ServerEvent::prompt("Please enter your username"));                
+                    
+                    loop{
+                        name = read_input(lines, sender).await?.trim().to_ascii_lowercase();
 
-                    // search for user
-                    let (username, _) = find_user_login(name.clone());
+                        // search for user
+                        if users.contains(&name).await{
+                            let _ = sender.push(ServerEvent::prompt("username taken"));
+                            continue;
+                        }
 
-                    if !username.eq(""){
-                        broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("username taken".to_string()) }).await?;
-                        continue;
+                        if !name.is_empty() && name.chars().all(char::is_alphanumeric) {
+                            break;
+                        }
+                        let _ = sender.push(ServerEvent::prompt("Username must only contain alpha-numeric characters"));
                     }
+                    
+                    let _ = sender.push(ServerEvent::prompt("Please enter your password"));
 
-                    if name.chars().all(char::is_alphanumeric) {
-                        break;
+                    let pwd = read_input(lines, sender).await?.trim().to_string();
+                    let pwd_hash = task::spawn_blocking(move || password::hash(&pwd)).await?;
+
+                    //the check above is only a hint, another client may have taken the name since
+                    match users.register(&name, &pwd_hash).await {
+                        Ok(()) => break,
+                        Err(RegisterError::Taken) => {
+                            let _ = sender.push(ServerEvent::error(ErrorKind::UsernameTaken, "username taken"));
+                        }
+                        Err(e) => return Err(e.into()),
                     }
-                    broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Username must only contain alpha-numeric characters\n\r".to_string()) })
-                    .await?;
                 }
-                
-                broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Please enter your password".to_string()) }).await?;
-
-                let pwd = (match lines.next().await { 
-                    None => Err("peer disconnected immediately")?,
-                    Some(line) => line?,
-                }).trim().to_string();
-
-                register(name.clone(), pwd.to_string());
                 break;
             },
             _ => {
-                broker.send(Event::SysMessage { stream: (Arc::clone(&stream)), msg: ("Please select Y or N".to_string()) }).await?;
+                let _ = sender.push(ServerEvent::prompt("Please select Y or N"));
             },            
         }
 
-        if !name.eq(""){
+        if !name.is_empty(){
             break;
         }
     }
-    
+    Ok(Some(name))
+}
+
+fn too_large(len: usize, max: usize) -> ServerEvent {
+    ServerEvent::error(ErrorKind::TooLarge, format!("Message of {} bytes is over the limit of {} bytes", len, max))
+}
+
+async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream, users: Arc<UserStore>, tokens: Arc<Tokens>, lockout: Arc<Lockout>, queues: Arc<Queues>, config: Arc<Config>) -> Result<()> {
+
+    let stream = Arc::new(stream);
+    let ip = stream.peer_addr()?.ip();
+    let reader = BufReader::new(&*stream);
 
     
-    // let mut name;
-    
-    
-    
-    let (_shutdown_sender, shutdown_receiver) = mpsc::unbounded::<Void>(); //only purpose is to get dropped
+    let mut lines = FrameReader::new(reader, config.max_message_bytes);
+
+    //everything for the client goes through its queue and writer, the broker never writes to a socket
+    let (sender, mut receiver) = queues.open(&stream.peer_addr()?.to_string());
+    let writer = {
+        let stream = Arc::clone(&stream);
+        task::spawn(async move {
+            let written = connection_writer_loop(&mut receiver, stream).await;
+            (receiver, written)
+        })
+    };
+
+    if config.login_prompts {
+        let _ = sender.push(ServerEvent::prompt("Do you have an account? Y/N"));
+    }
+
+    //a hello starts the handshake, anything typed starts the prompts if they are on.
+    //Returning before login drops the sender, the writer sends what is left and hangs up,
+    //which also frees the connection's place for a client that never logs in.
+    let login = async {
+        loop {
+            let event = match read_login(&mut lines, &sender, config.login_prompts).await? {
+                ClientRequest::Hello { version } if version == protocol::PROTOCOL_VERSION => {
+                    let _ = sender.push(ServerEvent::Hello { version });
+                    match handshake(&mut lines, &sender, ip, &users, &tokens, &lockout, &config).await? {
+                        Some(login) => return Result::<_>::Ok(Some(login)),
+                        None => return Ok(None),
+                    }
+                }
+                ClientRequest::Hello { version } => {
+                    let message = format!("Protocol version {} is not supported, this server speaks version {}", version, protocol::PROTOCOL_VERSION);
+                    let _ = sender.push(ServerEvent::error(ErrorKind::UnsupportedVersion, message));
+                    return Ok(None);
+                }
+                ClientRequest::Input { text } if config.login_prompts => {
+                    match prompt_login(text, &mut lines, &sender, ip, &users, &lockout, &config).await? {
+                        Some(name) => return Ok(Some((name, None))),
+                        None => return Ok(None),
+                    }
+                }
+                _ => ServerEvent::error(ErrorKind::Unexpected, "Please start with a hello"),
+            };
+            let _ = sender.push(event);
+        }
+    };
+    let login_timeout = Duration::from_secs(config.login_timeout_secs);
+    let (name, resumed) = match future::timeout(login_timeout, login).await {
+        Ok(login) => match login? {
+            Some(login) => login,
+            None => return Ok(()),
+        },
+        Err(_) => {
+            debug!("{} did not log in within {:?}", ip, login_timeout);
+            let _ = sender.push(ServerEvent::error(ErrorKind::LoginTimeout, format!("No login within {} seconds, disconnecting", config.login_timeout_secs)));
+            return Ok(());
+        }
+    };
+    sender.set_name(&name);
+
+    //a password login starts a session, its token spares the client the password next time
+    let (session, token) = match resumed {
+        Some(session) => (session, None),
+        None => {
+            let (token, session) = tokens.issue(&name).await?;
+            (session, Some(token))
+        }
+    };
+    let current = session.id.clone();
+    let id = sender.id();
+
+    let (accepted_sender, accepted_receiver) = oneshot::channel();
     //handle new connection
     broker.send(
         Event::NewPeer {
-            name: name.clone(), stream: Arc::clone(&stream),shutdown: shutdown_receiver
-        })
-    .await?;
-    
-    broker.send(
-        Event::SysMessage { 
-            stream: (Arc::clone(&stream)), msg: (format!("Welcome {}\n\r",name))
+            name: name.clone(), id, session, token: token.clone(), addr: stream.peer_addr()?.to_string(), sender: sender.clone(), accepted: accepted_sender
         })
     .await?;
+    let refused = match accepted_receiver.await {
+        Ok(Ok(())) => None,
+        Ok(Err(reason)) => Some(reason),
+        //the broker restarted before it answered
+        Err(_) => Some(ServerEvent::info("The login did not go through, please try again")),
+    };
+    if let Some(reason) = refused {
+        let _ = sender.push(reason);
+        info!("Refused a session of {}", name);
+        if token.is_some() {
+            tokens.revoke(&name, &current).await?;
+        }
+        return Ok(());
+    }
+
+    let result = request_loop(&mut lines, &mut broker, &sender, &name, &current, &config).await;
+
+    //the client is gone or the writer hung up, what was not written goes back to the broker
+    sender.stop();
+    let (pending, written) = writer.await;
+    broker.send(Event::Disconnect { name, id, pending }).await?;
+    result.and(written)
+}
 
-    while let Some(line) = lines.next().await {
+//Passes the requests of a logged in client on to the broker until the connection ends
+async fn request_loop(lines: &mut Lines<'_>, broker: &mut Sender<Event>, sender: &PeerSender, name: &str, current: &str, config: &Config) -> Result<()> {
+    while let Some(frame) = lines.next().await {
         
-        let line = line?;
-        let (dest, msg) = match line.find(':') { //splits message between destionation and message
-            None => continue,
-            Some(idx) => (&line[..idx], line[idx + 1 ..].trim()),
+        let line = match frame? {
+            Frame::Line(line) => line,
+            Frame::TooLong(len) => {
+                let _ = sender.push(too_large(len, config.max_message_bytes));
+                continue;
+            }
         };
-        let dest: Vec<String> = dest.split(',').map(|name| name.trim().to_string()).collect();
-        let msg: String = msg.to_string();
+        let (to, content, id) = match protocol::decode::<ClientRequest>(&line) {
+            Ok(ClientRequest::Message { to, content, id }) => (to, content, id),
+            Ok(ClientRequest::CreateRoom { room, topic }) => {
+                broker.send(Event::Room { from: name.to_string(), op: RoomOp::Create { room, topic } }).await?;
+                continue;
+            }
+            Ok(ClientRequest::JoinRoom { room }) => {
+                broker.send(Event::Room { from: name.to_string(), op: RoomOp::Join { room } }).await?;
+                continue;
+            }
+            Ok(ClientRequest::LeaveRoom { room }) => {
+                broker.send(Event::Room { from: name.to_string(), op: RoomOp::Leave { room } }).await?;
+                continue;
+            }
+            Ok(ClientRequest::ListRooms) => {
+                broker.send(Event::Room { from: name.to_string(), op: RoomOp::List }).await?;
+                continue;
+            }
+            Ok(ClientRequest::ListMembers { room }) => {
+                broker.send(Event::Room { from: name.to_string(), op: RoomOp::Members { room } }).await?;
+                continue;
+            }
+            Ok(ClientRequest::History { room, since }) => {
+                broker.send(Event::Room { from: name.to_string(), op: RoomOp::History { room, since } }).await?;
+                continue;
+            }
+            Ok(ClientRequest::ListSessions) => {
+                broker.send(Event::Sessions { from: name.to_string(), current: current.to_string(), revoke: None }).await?;
+                continue;
+            }
+            Ok(ClientRequest::RevokeSession { id }) => {
+                broker.send(Event::Sessions { from: name.to_string(), current: current.to_string(), revoke: Some(id) }).await?;
+                continue;
+            }
+            Ok(ClientRequest::Block { user }) => {
+                broker.send(Event::Block { from: name.to_string(), user, block: true }).await?;
+                continue;
+            }
+            Ok(ClientRequest::Unblock { user }) => {
+                broker.send(Event::Block { from: name.to_string(), user, block: false }).await?;
+                continue;
+            }
+            Ok(ClientRequest::Unlock { target }) => {
+                broker.send(Event::Unlock { from: name.to_string(), target }).await?;
+                continue;
+            }
+            Ok(ClientRequest::Stats) => {
+                broker.send(Event::Stats { from: name.to_string() }).await?;
+                continue;
+            }
+            Ok(ClientRequest::FileStart { transfer, to, filename, size, sha256, id }) => {
+                let to = to.iter().map(|name| name.trim().to_ascii_lowercase()).collect();
+                broker.send(Event::FileStart { from: name.to_string(), transfer, to, filename, size, sha256, id }).await?;
+                continue;
+            }
+            Ok(ClientRequest::FileChunk { transfer, offset, data }) => {
+                broker.send(Event::FileChunk { from: name.to_string(), transfer, offset, data }).await?;
+                continue;
+            }
+            //accepting is resuming with nothing received yet
+            Ok(ClientRequest::FileAccept { from, transfer }) => {
+                let owner = from.trim().to_ascii_lowercase();
+                broker.send(Event::FileResume { from: name.to_string(), sender: owner, transfer, offset: 0 }).await?;
+                continue;
+            }
+            Ok(ClientRequest::FileReject { from, transfer }) => {
+                let owner = from.trim().to_ascii_lowercase();
+                broker.send(Event::FileReject { from: name.to_string(), sender: owner, transfer }).await?;
+                continue;
+            }
+            Ok(ClientRequest::FileResume { from, transfer, offset }) => {
+                let owner = from.trim().to_ascii_lowercase();
+                broker.send(Event::FileResume { from: name.to_string(), sender: owner, transfer, offset }).await?;
+                continue;
+            }
+            Ok(ClientRequest::FileAck { from, transfer, offset }) => {
+                let owner = from.trim().to_ascii_lowercase();
+                broker.send(Event::FileAck { from: name.to_string(), sender: owner, transfer, offset }).await?;
+                continue;
+            }
+            Ok(ClientRequest::Input { .. }) | Ok(ClientRequest::Hello { .. }) | Ok(ClientRequest::Login { .. }) | Ok(ClientRequest::Register { .. }) | Ok(ClientRequest::ResumeSession { .. }) => {
+                let _ = sender.push(ServerEvent::error(ErrorKind::Unexpected, "Already logged in"));
+                continue;
+            }
+            Err(e) => {
+                let _ = sender.push(ServerEvent::error(ErrorKind::Malformed, e.to_string()));
+                continue;
+            }
+        };
+        let to: Vec<String> = to.iter().map(|name| name.trim().to_ascii_lowercase()).collect();
         
         //sends messgage
         broker.send(Event::Message {
-            from: name.clone(),
-            to: dest,
-            msg,
+            from: name.to_string(),
+            to,
+            msg: content,
+            id,
         }).await?;
     }
     Ok(())
 }
 
-async fn connection_writer_loop(messages: &mut Receiver<String>, stream: Arc<TcpStream>, shutdown: Receiver<Void>,) -> Result<()> {
+//Writes the queue to the socket until it ends, then hangs up, which also ends the reading side
+async fn connection_writer_loop(messages: &mut PeerReceiver, stream: Arc<TcpStream>) -> Result<()> {
     let mut stream = &*stream;
-    let mut messages = messages.fuse();
-    let mut shutdown = shutdown.fuse();
+    let mut written = Ok(());
+    while let Some(msg) = messages.next().await {
+        if let Err(e) = stream.write_all(protocol::encode(&msg)?.as_bytes()).await {
+            //a dead socket only ends this connection
+            written = Err(e.into());
+            break;
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    written
+}
 
-    loop { 
-        select! {
-            msg = messages.next().fuse() => match msg {
-                Some(msg) => stream.write_all(msg.as_bytes()).await?,
-                None => break,
-            },
-            void = shutdown.next().fuse() => match void {
-                Some(void) => match void {},
-                None => break,
+//Queues an event for every session of a connected user, users that are not connected are skipped
+async fn send_to(peers: &mut Peers, name: &str, event: ServerEvent) {
+    if let Some(sessions) = peers.get_mut(name) {
+        for session in sessions {
+            if session.sender.push(event.clone()).is_err() {
+                debug!("Dropped an event for {}, the connection is closing", name);
             }
         }
     }
-    Ok(())
+}
 
+fn shutdown_notice() -> ServerEvent {
+    ServerEvent::info("The server is shutting down, please reconnect later")
 }
 
-async fn broker_loop(events: Receiver<Event>) -> Result<()>{
-    let (disconnect_sender, mut disconnect_receiver) = mpsc::unbounded::<(String, Receiver<String>)>();
-    let mut peers: HashMap<String, Sender<String>> = HashMap::new();
-    let mut events = events.fuse();
-    
-    //#? Create new event to handle files and other data types
+//Drops connection `id` of `name`, returns how many sessions the account has left.
+//None if the broker did not know the connection, an account left without any is removed.
+fn remove_session(peers: &mut Peers, name: &str, id: u64) -> Option<usize> {
+    let sessions = peers.get_mut(name)?;
+    let before = sessions.len();
+    sessions.retain(|session| session.id != id);
+    let remaining = sessions.len();
+    if remaining == 0 {
+        if before == 0 {
+            warn!("{} was listed without any session", name);
+        }
+        peers.remove(name);
+    }
+    (remaining < before).then_some(remaining)
+}
 
-    //while event exists, we match the event and run it
+//Tells everyone else that `name` came or went
+async fn broadcast_presence(peers: &mut Peers, name: &str, online: bool) {
+    let others: Vec<String> = peers.keys().filter(|other| *other != name).cloned().collect();
+    for other in others {
+        send_to(peers, &other, ServerEvent::Presence { user: name.to_string(), online }).await;
+    }
+}
+
+async fn save_room(storage: &Arc<dyn Storage>, room: RoomRecord) {
+    let storage = Arc::clone(storage);
+    let name = room.name.clone();
+    if let Err(e) = task::spawn_blocking(move || storage.save_room(&room)).await {
+        error!("Failed to save room {}: {}", name, e);
+    }
+}
+
+//Stores a direct message for a user that is not connected, false if it was dropped
+async fn queue_offline(offline: &Arc<OfflineQueue>, msg: StoredMessage) -> bool {
+    let offline = Arc::clone(offline);
+    let to = msg.to.clone();
+    match task::spawn_blocking(move || offline.push(&msg)).await {
+        Ok(queued) => queued,
+        Err(e) => {
+            error!("Failed to queue message for {}: {}", to, e);
+            false
+        }
+    }
+}
+
+//Hands the messages queued while the user was away to their writer, oldest first
+async fn deliver_offline(offline: &Arc<OfflineQueue>, storage: &Arc<dyn Storage>, blocks: &Blocks, name: &str, peer: &PeerSender) {
+    let messages = {
+        let offline = Arc::clone(offline);
+        let recipient = name.to_string();
+        match task::spawn_blocking(move || offline.take(&recipient)).await {
+            //the user may have blocked a sender after the message was queued
+            Ok(messages) => messages.into_iter().filter(|msg| !blocks.is_blocked(name, &msg.from)).collect::<Vec<_>>(),
+            Err(e) => {
+                error!("Failed to load queued messages for {}: {}", name, e);
+                return;
+            }
+        }
+    };
+    if messages.is_empty() {
+        return;
+    }
+
+    debug!("Delivering {} queued messages to {}", messages.len(), name);
+    let _ = peer.push(ServerEvent::info(format!("{} messages arrived while you were away", messages.len())));
+    for msg in messages {
+        if peer.push(ServerEvent::Message { from: msg.from.clone(), content: msg.content.clone() }).is_err() {
+            warn!("Queued message for {} could not be delivered, the connection is closing", name);
+        }
+        let storage = Arc::clone(storage);
+        if let Err(e) = task::spawn_blocking(move || storage.append_history(&msg)).await {
+            error!("Failed to record history: {}", e);
+        }
+    }
+}
+
+//Queues the direct messages a writer had not sent yet when its connection closed
+async fn requeue_pending(offline: &Arc<OfflineQueue>, name: &str, pending: Vec<ServerEvent>) {
+    for event in pending {
+        if let ServerEvent::Message { from, content } = event {
+            if !queue_offline(offline, StoredMessage::new(&from, name, &content)).await {
+                warn!("Dropped a pending message for {}, queue is full", name);
+            }
+        }
+    }
+}
+
+async fn block_request(blocks: &mut Blocks, peers: &mut Peers, storage: &Arc<dyn Storage>, users: &UserStore, from: &str, user: &str, block: bool) {
+    let user = user.trim().to_ascii_lowercase();
+    if !users.contains(&user).await {
+        let event = ServerEvent::error(ErrorKind::NoSuchUser, format!("There is no user {}", user));
+        return send_to(peers, from, event).await;
+    }
+
+    let (changed, notice) = if block {
+        (blocks.block(from, &user), format!("Blocked {}", user))
+    } else {
+        (blocks.unblock(from, &user), format!("Unblocked {}", user))
+    };
+    if let Some(record) = changed {
+        let storage = Arc::clone(storage);
+        let result = task::spawn_blocking(move || {
+            if block {
+                storage.add_block(&record)
+            } else {
+                storage.remove_block(&record)
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Failed to save block list of {}: {}", from, e);
+        }
+    }
+    send_to(peers, from, ServerEvent::info(notice)).await;
+}
+
+//Lifts a login lockout, only for the admins in the configuration
+async fn unlock_request(lockout: &Lockout, peers: &mut Peers, config: &Config, from: &str, target: &str) {
+    if !config.admins.iter().any(|admin| admin == from) {
+        return send_to(peers, from, ServerEvent::error(ErrorKind::Forbidden, "Only admins may unlock accounts")).await;
+    }
+    let target = target.trim().to_ascii_lowercase();
+    let notice = if lockout.unlock(&target, from).await {
+        info!("{} unlocked {}", from, target);
+        format!("Unlocked {}", target)
+    } else {
+        format!("{} is not locked", target)
+    };
+    send_to(peers, from, ServerEvent::info(notice)).await;
+}
+
+//Lists or revokes the sessions of `from`, the list only goes to the connection that asked
+async fn session_request(tokens: &Tokens, peers: &mut Peers, from: &str, current: &str, revoke: Option<String>) {
+    let id = match revoke {
+        Some(id) => id,
+        None => {
+            let sessions = tokens.list(from).await.into_iter()
+                .map(|session| SessionInfo { current: session.id == current, id: session.id, created_at: session.created_at, expires_at: session.expires_at })
+                .collect();
+            let event = ServerEvent::Sessions { sessions };
+            for session in peers.get_mut(from).into_iter().flatten().filter(|session| session.login == current) {
+                let _ = session.sender.push(event.clone());
+            }
+            return;
+        }
+    };
+    match tokens.revoke(from, &id).await {
+        Ok(true) => {
+            info!("{} revoked session {}", from, id);
+            //dropped sessions close, their writers flush the notice and hang up
+            if let Some(sessions) = peers.get_mut(from) {
+                sessions.retain(|session| {
+                    if session.login != id {
+                        return true;
+                    }
+                    let _ = session.sender.push(ServerEvent::info("Logged out, this session was revoked"));
+                    false
+                });
+                if sessions.is_empty() {
+                    peers.remove(from);
+                    broadcast_presence(peers, from, false).await;
+                }
+            }
+            send_to(peers, from, ServerEvent::info(format!("Revoked session {}", id))).await;
+        }
+        Ok(false) => send_to(peers, from, ServerEvent::error(ErrorKind::NoSuchSession, format!("You have no session {}", id))).await,
+        Err(e) => {
+            error!("Failed to revoke session {} of {}: {}", id, from, e);
+            send_to(peers, from, ServerEvent::info(format!("Could not revoke session {}, try again", id))).await;
+        }
+    }
+}
+
+//Outgoing queue depths of every connection and the connection counts, only for the admins in the configuration
+async fn stats_request(peers: &mut Peers, connections: &Connections, config: &Config, from: &str) {
+    if !config.admins.iter().any(|admin| admin == from) {
+        return send_to(peers, from, ServerEvent::error(ErrorKind::Forbidden, "Only admins may see the server stats")).await;
+    }
+    let mut queues: Vec<QueueInfo> = peers.iter()
+        .flat_map(|(user, sessions)| sessions.iter().map(move |session| (user, session.sender.stats())))
+        .map(|(user, stats)| QueueInfo { user: user.clone(), depth: stats.depth, peak: stats.peak, dropped: stats.dropped, spilled: stats.spilled })
+        .collect();
+    queues.sort_by(|a, b| b.depth.cmp(&a.depth).then_with(|| a.user.cmp(&b.user)));
+    send_to(peers, from, ServerEvent::Stats { queues, connections: connections.info() }).await;
+}
+
+async fn room_request(rooms: &mut Rooms, peers: &mut Peers, storage: &Arc<dyn Storage>, blocks: &Blocks, from: &str, op: RoomOp) {
+    let (requested, result) = match op {
+        RoomOp::List => {
+            let event = ServerEvent::Rooms { rooms: rooms.list(from) };
+            return send_to(peers, from, event).await;
+        }
+        RoomOp::Members { room } => {
+            let result = rooms::normalize(&room).and_then(|name| {
+                let members = rooms.members(&name, |user| peers.contains_key(user))?;
+                Ok(ServerEvent::Members { room: name, members })
+            });
+            match result {
+                Ok(event) => send_to(peers, from, event).await,
+                Err(e) => send_to(peers, from, e.to_event(&room)).await,
+            }
+            return;
+        }
+        //what a member missed, left out are senders they blocked
+        RoomOp::History { room, since } => {
+            let name = match rooms::normalize(&room).and_then(|name| rooms.members_of(&name, from).map(|_| name)) {
+                Ok(name) => name,
+                Err(e) => return send_to(peers, from, e.to_event(&room)).await,
+            };
+            let result = {
+                let storage = Arc::clone(storage);
+                let name = name.clone();
+                task::spawn_blocking(move || storage.history(&name, since, HISTORY_LIMIT)).await
+            };
+            let messages = match result {
+                Ok(messages) => messages.into_iter()
+                    .filter(|msg| !blocks.is_blocked(from, &msg.from))
+                    .map(|msg| HistoryMessage { from: msg.from, content: msg.content, sent_at: msg.sent_at })
+                    .collect(),
+                Err(e) => {
+                    error!("Failed to load history of {}: {}", name, e);
+                    Vec::new()
+                }
+            };
+            return send_to(peers, from, ServerEvent::History { room: name, messages }).await;
+        }
+        RoomOp::Create { room, topic } => {
+            let result = rooms::normalize(&room).and_then(|name| rooms.create(&name, topic, from).cloned());
+            (room, result)
+        }
+        RoomOp::Join { room } => {
+            let result = rooms::normalize(&room).and_then(|name| rooms.join(&name, from).cloned());
+            (room, result)
+        }
+        RoomOp::Leave { room } => {
+            let result = rooms::normalize(&room).and_then(|name| rooms.leave(&name, from).cloned());
+            match result {
+                Ok(record) => {
+                    let event = ServerEvent::RoomLeft { room: record.name.clone(), user: from.to_string() };
+                    send_to(peers, from, event.clone()).await;
+                    for member in &record.members {
+                        send_to(peers, member, event.clone()).await;
+                    }
+                    save_room(storage, record).await;
+                }
+                Err(e) => send_to(peers, from, e.to_event(&room)).await,
+            }
+            return;
+        }
+    };
+
+    //created or joined, every member including the new one hears about it
+    match result {
+        Ok(record) => {
+            let event = ServerEvent::RoomJoined { room: record.name.clone(), user: from.to_string() };
+            for member in &record.members {
+                send_to(peers, member, event.clone()).await;
+            }
+            save_room(storage, record).await;
+        }
+        Err(e) => send_to(peers, from, e.to_event(&requested)).await,
+    }
+}
+
+//Drops idle transfers, telling the recipients and the sender
+async fn expire_transfers(transfers: &mut Transfers, peers: &mut Peers) {
+    for expired in transfers.expire() {
+        debug!("Transfer {} of {} expired", expired.transfer, expired.from);
+        let event = ServerEvent::FileCancelled { from: expired.from.clone(), transfer: expired.transfer.clone() };
+        for recipient in &expired.recipients {
+            send_to(peers, recipient, event.clone()).await;
+        }
+        send_to(peers, &expired.from, ServerEvent::FileFinished { transfer: expired.transfer, complete: false }).await;
+    }
+}
+
+//Tells the sender of a transfer it may send more, if the recipients made room
+async fn grant(transfers: &mut Transfers, peers: &mut Peers, sender: &str, transfer: &str) {
+    if let Some(until) = transfers.grant(sender, transfer, |name| peers.contains_key(name)) {
+        send_to(peers, sender, ServerEvent::FileGrant { transfer: transfer.to_string(), until }).await;
+    }
+}
+
+//Runs the broker, starting it over whenever it fails or panics. The events wait in the channel meanwhile,
+//the connections it knew are closed and their clients log in again to the new one.
+async fn supervise_broker(mut events: Receiver<Event>, storage: Arc<dyn Storage>, users: Arc<UserStore>, tokens: Arc<Tokens>, lockout: Arc<Lockout>, connections: Arc<Connections>, config: Arc<Config>) {
     loop {
-        let event = select! {
-            event = events.next().fuse() => match event {
-                None => break,
-                Some(event) => event,
-            },
-            disconnect = disconnect_receiver.next().fuse() => {
-               
-                // match disconnect{
-                //     Some(disconnect) => (disconnect),
-                //     None => continue,
-                // };
-                // let (name, _pending_messages) = disconnect;
-                let (name, _pending_messages) = disconnect.unwrap(); //##ASK Option -> Result
-                assert!(peers.remove(&name).is_some());
-                continue;
-            },
+        let broker = broker_loop(&mut events, Arc::clone(&storage), Arc::clone(&users), Arc::clone(&tokens), Arc::clone(&lockout), Arc::clone(&connections), Arc::clone(&config));
+        let failure = match AssertUnwindSafe(broker).catch_unwind().await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(panic) => panic_message(&*panic).to_string(),
         };
+        error!("Broker failed, restarting it in {:?}: {}", BROKER_RESTART_DELAY, failure);
+        task::sleep(BROKER_RESTART_DELAY).await;
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("unknown panic", String::as_str),
+    }
+}
 
+async fn broker_loop(events: &mut Receiver<Event>, storage: Arc<dyn Storage>, users: Arc<UserStore>, tokens: Arc<Tokens>, lockout: Arc<Lockout>, connections: Arc<Connections>, config: Arc<Config>) -> Result<()>{
+    let offline = Arc::new(OfflineQueue::new(Arc::clone(&storage), config.offline_max_per_user, config.offline_max_age_secs));
+    let mut peers: Peers = HashMap::new();
+    let mut shutting_down = false;
+    let mut transfers = Transfers::new(Duration::from_secs(config.resume_window_secs));
+    let mut rooms = {
+        let storage = Arc::clone(&storage);
+        task::spawn_blocking(move || Rooms::load(&*storage)).await?
+    };
+    let mut blocks = {
+        let storage = Arc::clone(&storage);
+        task::spawn_blocking(move || Blocks::load(&*storage)).await?
+    };
+    //#? Create new event to handle files and other data types
+
+    //while event exists, we match the event and run it
+    while let Some(event) = events.next().await {
         match event {
+            Event::Disconnect { name, id, mut pending } => {
+                match remove_session(&mut peers, &name, id) {
+                    Some(0) => broadcast_presence(&mut peers, &name, false).await,
+                    Some(_) => {}
+                    //kicked, revoked or the broker restarted, it was closed and flushed already
+                    None => debug!("Connection {} of {} ended after its session", id, name),
+                }
+                //with another session left the pending messages reached it already
+                if !peers.contains_key(&name) {
+                    requeue_pending(&offline, &name, pending.drain().await).await;
+                    //senders it held back can go on with the others
+                    for (sender, transfer, until) in transfers.grant_all(|name| peers.contains_key(name)) {
+                        send_to(&mut peers, &sender, ServerEvent::FileGrant { transfer, until }).await;
+                    }
+                }
+                if shutting_down && peers.is_empty() {
+                    break;
+                }
+            }
+            Event::Shutdown => {
+                info!("Closing {} connections", peers.values().map(Vec::len).sum::<usize>());
+                shutting_down = true;
+                for session in peers.values().flatten() {
+                    let _ = session.sender.push(shutdown_notice());
+                    session.sender.close();
+                }
+                if peers.is_empty() {
+                    break;
+                }
+            }
+            Event::ShutdownNow { done } => {
+                for (name, sessions) in peers.drain() {
+                    warn!("{} connection(s) of {} did not close in time", sessions.len(), name);
+                    //every session got the same messages, the one furthest behind has them all
+                    let mut pending = Vec::new();
+                    for session in &sessions {
+                        let rest = session.sender.abandon().await;
+                        if rest.len() > pending.len() {
+                            pending = rest;
+                        }
+                    }
+                    requeue_pending(&offline, &name, pending).await;
+                }
+                let _ = done.send(());
+                break;
+            }
             //sending message to each?? destination
-            Event::Message { from, to, msg } => {
+            Event::Message { from, to, msg, id } => {
                 for addr in to {
-                    if let Some(peer) = peers.get_mut(&addr) {
-                        let msg = format!("{}: {}\n\r", from, msg);
-                        println!("{}",msg);
-                        match peer.send(msg).await{
-                            Ok(_) => (),
-                            Err(why) => print!("{}", why),
+                    if rooms::is_room(&addr) {
+                        let members = rooms::normalize(&addr)
+                            .and_then(|room| Ok((room.clone(), rooms.members_of(&room, &from)?.to_vec())));
+                        let (room, members) = match members {
+                            Ok(members) => members,
+                            Err(e) => {
+                                send_to(&mut peers, &from, e.to_event(&addr)).await;
+                                continue;
+                            }
+                        };
+                        debug!("{} -> {}: {}", from, room, msg);
+                        let event = ServerEvent::RoomMessage { room: room.clone(), from: from.clone(), content: msg.clone() };
+                        for member in members.iter().filter(|member| **member != from && !blocks.is_blocked(member, &from)) {
+                            send_to(&mut peers, member, event.clone()).await;
+                        }
+
+                        let record = StoredMessage::new(&from, &room, &msg);
+                        let storage = Arc::clone(&storage);
+                        if let Err(e) = task::spawn_blocking(move || storage.append_history(&record)).await {
+                            error!("Failed to record history: {}", e);
                         }
+                        send_to(&mut peers, &from, ServerEvent::Delivery { to: room, status: DeliveryStatus::Delivered, id }).await;
+                        continue;
                     }
+
+                    let status = if !users.contains(&addr).await {
+                        DeliveryStatus::UnknownUser
+                    } else if blocks.is_blocked(&addr, &from) {
+                        debug!("{} -> {} (blocked)", from, addr);
+                        DeliveryStatus::Blocked
+                    } else if peers.contains_key(&addr) {
+                        debug!("{} -> {}: {}", from, addr, msg);
+                        let event = ServerEvent::Message { from: from.clone(), content: msg.clone() };
+                        send_to(&mut peers, &addr, event).await;
+
+                        let record = StoredMessage::new(&from, &addr, &msg);
+                        let storage = Arc::clone(&storage);
+                        if let Err(e) = task::spawn_blocking(move || storage.append_history(&record)).await {
+                            error!("Failed to record history: {}", e);
+                        }
+                        DeliveryStatus::Delivered
+                    } else {
+                        debug!("{} -> {} (offline): {}", from, addr, msg);
+                        if queue_offline(&offline, StoredMessage::new(&from, &addr, &msg)).await {
+                            DeliveryStatus::Queued
+                        } else {
+                            DeliveryStatus::QueueFull
+                        }
+                    };
+                    send_to(&mut peers, &from, ServerEvent::Delivery { to: addr, status, id }).await;
                 }
             }
-            Event::SysMessage {stream, msg } => {
-                let mut stream = &*stream;
-                let msg = format!("SYS:{}\n\r", msg);
-                // match stream.write_all(msg.as_bytes()).await{ //##ASK "?"" not applic?
-                //     Ok(_) => (),
-                //     Err(why) => println!("{}",why),
-                // }
+            //files are relayed to connected users only, they are too big to queue
+            Event::FileStart { from, transfer, to, filename, size, sha256, id } => {
+                expire_transfers(&mut transfers, &mut peers).await;
+                let mut recipients = Vec::new();
+                let mut statuses = Vec::new();
+                for addr in to {
+                    if rooms::is_room(&addr) {
+                        let event = ServerEvent::error(ErrorKind::Unsupported, format!("Files can only be sent to users, not to {}", addr));
+                        send_to(&mut peers, &from, event).await;
+                        continue;
+                    }
+                    let status = if !users.contains(&addr).await {
+                        DeliveryStatus::UnknownUser
+                    } else if blocks.is_blocked(&addr, &from) {
+                        DeliveryStatus::Blocked
+                    } else if peers.contains_key(&addr) {
+                        recipients.push(addr.clone());
+                        DeliveryStatus::Delivered
+                    } else {
+                        DeliveryStatus::Offline
+                    };
+                    statuses.push(ServerEvent::Delivery { to: addr, status, id });
+                }
 
-                stream.write_all(msg.as_bytes()).await?;
-                
+                let offer = ServerEvent::FileOffer { from: from.clone(), transfer: transfer.clone(), filename: filename.clone(), size, sha256: sha256.clone() };
+                match transfers.start(&from, &transfer, recipients.clone(), Offer { filename, size, sha256 }) {
+                    Ok(Started::New) => (),
+                    Ok(Started::Nobody) => {
+                        for status in statuses {
+                            send_to(&mut peers, &from, status).await;
+                        }
+                        send_to(&mut peers, &from, ServerEvent::FileFinished { transfer, complete: false }).await;
+                        continue;
+                    }
+                    //announced again after a reconnect, the recipients know it already
+                    Ok(Started::Resumed { offset }) => {
+                        debug!("{} resumes transfer {} at {}", from, transfer, offset);
+                        let until = transfers.grant(&from, &transfer, |name| peers.contains_key(name)).unwrap_or(offset);
+                        send_to(&mut peers, &from, ServerEvent::FileSeek { transfer, offset, until }).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        send_to(&mut peers, &from, e.to_event(&transfer)).await;
+                        continue;
+                    }
+                }
+                debug!("{} -> {:?}: offered {} bytes as transfer {}", from, recipients, size, transfer);
+                for recipient in &recipients {
+                    send_to(&mut peers, recipient, offer.clone()).await;
+                }
+                //the sender waits for a FileSeek, it comes once someone accepts
+                for status in statuses {
+                    send_to(&mut peers, &from, status).await;
+                }
+            }
+            //chunks are passed on as they come, nothing of the file is kept here
+            Event::FileChunk { from, transfer, offset, data } => {
+                expire_transfers(&mut transfers, &mut peers).await;
+                match transfers.chunk(&from, &transfer, offset, data.len(), |name| peers.contains_key(name)) {
+                    Ok((recipients, done)) => {
+                        let event = ServerEvent::FileChunk { from: from.clone(), transfer: transfer.clone(), offset, data };
+                        for recipient in &recipients {
+                            send_to(&mut peers, recipient, event.clone()).await;
+                        }
+                        if done {
+                            debug!("Transfer {} of {} complete", transfer, from);
+                            send_to(&mut peers, &from, ServerEvent::FileFinished { transfer, complete: true }).await;
+                        }
+                    }
+                    //chunks the sender sent before it saw a FileSeek, it starts over anyway
+                    Err(TransferError::OutOfOrder { expected }) => {
+                        debug!("Dropped chunk {} of transfer {}, expecting {}", offset, transfer, expected);
+                    }
+                    Err(e) => send_to(&mut peers, &from, e.to_event(&transfer)).await,
+                }
+            }
+            Event::FileResume { from, sender, transfer, offset } => {
+                expire_transfers(&mut transfers, &mut peers).await;
+                match transfers.resume(&sender, &transfer, &from, offset) {
+                    Ok(Resumed::Rewind { offset }) => {
+                        debug!("{} resumes transfer {} of {} at {}", from, transfer, sender, offset);
+                        let until = transfers.grant(&sender, &transfer, |name| peers.contains_key(name)).unwrap_or(offset);
+                        send_to(&mut peers, &sender, ServerEvent::FileSeek { transfer, offset, until }).await;
+                    }
+                    Ok(Resumed::Wait) => grant(&mut transfers, &mut peers, &sender, &transfer).await,
+                    Ok(Resumed::Complete) => {
+                        send_to(&mut peers, &sender, ServerEvent::FileFinished { transfer, complete: true }).await;
+                    }
+                    //expired or never existed, the recipient can throw its part away
+                    Err(TransferError::NoSuchTransfer) => {
+                        send_to(&mut peers, &from, ServerEvent::FileCancelled { from: sender, transfer }).await;
+                    }
+                    Err(e) => send_to(&mut peers, &from, e.to_event(&transfer)).await,
+                }
+            }
+            //a recipient wrote some more, the sender may be let further ahead
+            Event::FileAck { from, sender, transfer, offset } => {
+                match transfers.ack(&sender, &transfer, &from, offset) {
+                    Ok(()) => grant(&mut transfers, &mut peers, &sender, &transfer).await,
+                    //the last ack comes after the transfer is over
+                    Err(TransferError::NoSuchTransfer) => (),
+                    Err(e) => send_to(&mut peers, &from, e.to_event(&transfer)).await,
+                }
+            }
+            Event::FileReject { from, sender, transfer } => {
+                expire_transfers(&mut transfers, &mut peers).await;
+                match transfers.reject(&sender, &transfer, &from) {
+                    Ok(done) => {
+                        debug!("{} rejected transfer {} of {}", from, transfer, sender);
+                        send_to(&mut peers, &sender, ServerEvent::FileRejected { transfer: transfer.clone(), by: from }).await;
+                        match done {
+                            Some(complete) => send_to(&mut peers, &sender, ServerEvent::FileFinished { transfer, complete }).await,
+                            None => grant(&mut transfers, &mut peers, &sender, &transfer).await,
+                        }
+                    }
+                    Err(e) => send_to(&mut peers, &from, e.to_event(&transfer)).await,
+                }
+            }
+            Event::Room { from, op } => {
+                room_request(&mut rooms, &mut peers, &storage, &blocks, &from, op).await;
+            }
+            Event::Block { from, user, block } => {
+                block_request(&mut blocks, &mut peers, &storage, &users, &from, &user, block).await;
+            }
+            Event::Sessions { from, current, revoke } => {
+                session_request(&tokens, &mut peers, &from, &current, revoke).await;
+            }
+            Event::Unlock { from, target } => {
+                unlock_request(&lockout, &mut peers, &config, &from, &target).await;
+            }
+            Event::Stats { from } => {
+                stats_request(&mut peers, &connections, &config, &from).await;
             }
             //adding new peer
-            Event::NewPeer { name, stream, shutdown } => {
-                match peers.entry(name.clone()) {
-                    Entry::Occupied(..) => (),
-                    Entry::Vacant(entry) => {
-                        let (client_sender, mut client_receiver) = mpsc::unbounded();
-                        //register new peer in hashmap
-                        entry.insert(client_sender); 
-                        let mut disconnect_sender = disconnect_sender.clone();
-
-                        spawn_and_log_error(async move {
-                            let res = connection_writer_loop(&mut client_receiver, stream, shutdown).await;
-                            disconnect_sender.send((name, client_receiver))
-                            .await?;// sending peer name
-                            res
-                        });
+            Event::NewPeer { name, id, session, token, addr, sender, accepted } => {
+                let first_session = !peers.contains_key(&name);
+                if shutting_down {
+                    let _ = accepted.send(Err(shutdown_notice()));
+                    continue;
+                }
+                if !first_session && config.duplicate_login == DuplicateLogin::RejectNew {
+                    let notice = ServerEvent::info(format!("Someone logged in as you from {}, the login was refused", addr));
+                    send_to(&mut peers, &name, notice).await;
+                    let _ = accepted.send(Err(ServerEvent::error(ErrorKind::AlreadyLoggedIn, "You are already logged in on another connection")));
+                    continue;
+                }
+                if accepted.send(Ok(())).is_err() {
+                    //the connection is gone already, leave the other sessions alone
+                    debug!("Connection {} of {} ended before its login was accepted", id, name);
+                    continue;
+                }
 
+                let mut notice = None;
+                if let Some(sessions) = peers.get_mut(&name) {
+                    if config.duplicate_login == DuplicateLogin::KickOld {
+                        info!("{} logged in again, closing {} old session(s)", name, sessions.len());
+                        let kicked = ServerEvent::info(format!("Logged out, you logged in again from {}", addr));
+                        //dropped sessions close, their writers flush the notice and hang up
+                        for old in sessions.drain(..) {
+                            let _ = old.sender.push(kicked.clone());
+                        }
+                        notice = Some(ServerEvent::info("Your other session was logged out"));
+                    } else {
+                        //multi, reject_new did not get this far
+                        let count = sessions.len() + 1;
+                        let others = ServerEvent::info(format!("You also logged in from {}, now on {} connections", addr, count));
+                        send_to(&mut peers, &name, others).await;
+                        notice = Some(ServerEvent::info(format!("You are logged in on {} connections", count)));
                     }
                 }
+
+                //greeting first, then whatever arrived while the user was away
+                let _ = sender.push(ServerEvent::System(SystemNotice::Welcome { name: name.clone() }));
+                if let Some(token) = token {
+                    let _ = sender.push(ServerEvent::Session { id: session.id.clone(), token, expires_at: session.expires_at });
+                }
+                if first_session {
+                    deliver_offline(&offline, &storage, &blocks, &name, &sender).await;
+                }
+                if let Some(notice) = notice {
+                    let _ = sender.push(notice);
+                }
+                //offers nobody answered while the user was away
+                for offer in transfers.pending(&name) {
+                    let _ = sender.push(offer);
+                }
+                let mut online: Vec<String> = peers.iter().filter(|(other, sessions)| !sessions.is_empty() && **other != name).map(|(other, _)| other.clone()).collect();
+                online.push(name.clone());
+                online.sort();
+                let _ = sender.push(ServerEvent::Users { online });
+
+                //register new session in hashmap
+                peers.entry(name.clone()).or_default().push(Session { id, login: session.id, sender });
+                if first_session {
+                    broadcast_presence(&mut peers, &name, true).await;
+                }
             }
         }
     }
+    //every connection sent its disconnect before letting go of the channel
     drop(peers);    //drops peer map
-    drop(disconnect_sender); //drop disconnections channel
-    while let Some((_name, _pending_messages)) = disconnect_receiver.next().await {
-    }
     Ok(())
     
 
 }
    
 fn main() -> Result<()>{
-    task::block_on(accept_loop("127.0.0.1:8080"))//49983=>5
-}
\ No newline at end of file
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", config::usage());
+        return Ok(());
+    }
+    let config = match Config::load(&args) {
+        Ok(config) => Arc::new(config),
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("config error: {}", e);
+            }
+            std::process::exit(2);
+        }
+    };
+    env_logger::Builder::new().filter_level(config.log_level).init();
+
+    //SIGINT or SIGTERM shuts down gracefully, a second one does not wait for that
+    let (stop_sender, stop) = mpsc::unbounded();
+    let stopping = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if stopping.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        let _ = stop_sender.unbounded_send(());
+    })?;
+
+    let storage = storage::open(config.storage, &config.data_dir)?;
+
+    //user list is read once, every login after that is a lookup in memory
+    let users = Arc::new(UserStore::load(Arc::clone(&storage))?);
+    let tokens = Arc::new(Tokens::load(Arc::clone(&storage), config.session_ttl_secs)?);
+    let audit = Arc::new(AuditLog::open(&config.data_dir)?);
+    let lockout = Arc::new(Lockout::new(audit, config.lockout_threshold, config.ip_lockout_threshold, Duration::from_secs(config.lockout_secs)));
+    task::block_on(async {
+        let migrated = users.migrate_plaintext().await?;
+        if migrated > 0 {
+            info!("Hashed {} plaintext passwords", migrated);
+        }
+        info!("Loaded {} users from {:?} storage in {}", users.len().await, config.storage, config.data_dir.display());
+        accept_loop(config, users, tokens, lockout, storage, stop).await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Overflow;
+    use crate::storage::FlatFileStorage;
+    use async_std::future::timeout;
+    use chat_common::protocol::{ProtocolError, CHUNK_SIZE};
+
+    //A broker with alice and bob registered, driven through its channel like the connections do
+    struct Server {
+        dir: tempfile::TempDir,
+        broker: Sender<Event>,
+        queues: Queues,
+        tokens: Arc<Tokens>,
+        offline: Arc<OfflineQueue>,
+        handle: task::JoinHandle<()>,
+    }
+
+    struct Connection {
+        name: String,
+        login: String,
+        sender: PeerSender,
+        receiver: PeerReceiver,
+    }
+
+    async fn server(duplicate_login: DuplicateLogin) -> Server {
+        start(tempfile::tempdir().unwrap(), duplicate_login, Overflow::Disconnect).await
+    }
+
+    async fn start(dir: tempfile::TempDir, duplicate_login: DuplicateLogin, overflow: Overflow) -> Server {
+        let config = Arc::new(Config { data_dir: dir.path().to_path_buf(), duplicate_login, overflow, ..Config::default() });
+        let storage: Arc<dyn Storage> = Arc::new(FlatFileStorage::open(dir.path()).unwrap());
+        let users = Arc::new(UserStore::load(Arc::clone(&storage)).unwrap());
+        for name in ["alice", "bob"] {
+            users.register(name, "hash").await.unwrap();
+        }
+        let tokens = Arc::new(Tokens::load(Arc::clone(&storage), 60).unwrap());
+        let lockout = Arc::new(Lockout::new(Arc::new(AuditLog::open(dir.path()).unwrap()), 5, 5, Duration::from_secs(60)));
+        let offline = Arc::new(OfflineQueue::new(Arc::clone(&storage), 10, 60));
+        let queues = Queues::new(16, overflow, dir.path()).unwrap();
+        let (broker, events) = mpsc::channel(BROKER_QUEUE);
+        let connections = Arc::new(Connections::new(config.max_connections, config.max_connections_per_ip));
+        let handle = task::spawn(supervise_broker(events, storage, users, Arc::clone(&tokens), lockout, connections, config));
+        Server { dir, broker, queues, tokens, offline, handle }
+    }
+
+    impl Server {
+        //None if the broker refused the login
+        async fn login(&mut self, name: &str) -> Option<Connection> {
+            let (sender, receiver) = self.queues.open(name);
+            let (_, session) = self.tokens.issue(name).await.unwrap();
+            let login = session.id.clone();
+            let (accepted, answer) = oneshot::channel();
+            let event = Event::NewPeer { name: name.to_string(), id: sender.id(), session, token: None, addr: "test".to_string(), sender: sender.clone(), accepted };
+            self.broker.send(event).await.unwrap();
+            answer.await.unwrap().ok().map(|()| Connection { name: name.to_string(), login, sender, receiver })
+        }
+
+        //What connection_loop does once the client is gone
+        async fn disconnect(&mut self, connection: Connection) {
+            connection.sender.stop();
+            self.broker.send(Event::Disconnect { name: connection.name, id: connection.sender.id(), pending: connection.receiver }).await.unwrap();
+        }
+
+        //Messages `to` and returns the delivery status with everything `from` got before it
+        async fn message(&mut self, from: &mut Connection, to: &str) -> (DeliveryStatus, Vec<ServerEvent>) {
+            let event = Event::Message { from: from.name.clone(), to: vec![to.to_string()], msg: "ping".to_string(), id: None };
+            self.broker.send(event).await.unwrap();
+            let mut before = Vec::new();
+            loop {
+                match next(from).await {
+                    Some(ServerEvent::Delivery { status, .. }) => return (status, before),
+                    Some(event) => before.push(event),
+                    None => panic!("{} was disconnected", from.name),
+                }
+            }
+        }
+    }
+
+    async fn next(connection: &mut Connection) -> Option<ServerEvent> {
+        timeout(Duration::from_secs(5), connection.receiver.next()).await.expect("no event from the broker")
+    }
+
+    fn presence(events: &[ServerEvent], user: &str) -> Vec<bool> {
+        events.iter().filter_map(|event| match event {
+            ServerEvent::Presence { user: who, online } if who == user => Some(*online),
+            _ => None,
+        }).collect()
+    }
+
+    //Everything up to the end of a closed connection
+    async fn rest(connection: &mut Connection) -> Vec<ServerEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = next(connection).await {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn last_disconnect_goes_offline_and_keeps_pending_messages() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::KickOld).await;
+            let mut bob = server.login("bob").await.unwrap();
+            let alice = server.login("alice").await.unwrap();
+            //written by nobody, the client went away first
+            alice.sender.push(ServerEvent::Message { from: "bob".to_string(), content: "unread".to_string() }).unwrap();
+            server.disconnect(alice).await;
+
+            let (status, before) = server.message(&mut bob, "alice").await;
+            assert_eq!(status, DeliveryStatus::Queued);
+            assert_eq!(presence(&before, "alice"), vec![true, false]);
+            let queued: Vec<String> = server.offline.take("alice").unwrap().into_iter().map(|msg| msg.content).collect();
+            assert_eq!(queued, vec!["unread", "ping"]);
+        });
+    }
+
+    #[test]
+    fn kicked_session_ending_late_keeps_the_new_one() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::KickOld).await;
+            let mut bob = server.login("bob").await.unwrap();
+            let mut old = server.login("alice").await.unwrap();
+            let new = server.login("alice").await.unwrap();
+            //the old connection gets told and is closed, then its client goes
+            assert!(matches!(rest(&mut old).await.last(), Some(ServerEvent::System(SystemNotice::Info { .. }))));
+            server.disconnect(old).await;
+
+            let (status, before) = server.message(&mut bob, "alice").await;
+            assert_eq!(status, DeliveryStatus::Delivered);
+            assert_eq!(presence(&before, "alice"), vec![true]);
+            server.disconnect(new).await;
+            let (status, before) = server.message(&mut bob, "alice").await;
+            assert_eq!(status, DeliveryStatus::Queued);
+            assert_eq!(presence(&before, "alice"), vec![false]);
+        });
+    }
+
+    #[test]
+    fn multi_sessions_go_offline_with_the_last_in_either_order() {
+        task::block_on(async {
+            for first_goes_first in [true, false] {
+                let mut server = server(DuplicateLogin::Multi).await;
+                let mut bob = server.login("bob").await.unwrap();
+                let first = server.login("alice").await.unwrap();
+                let second = server.login("alice").await.unwrap();
+                let (gone, staying) = if first_goes_first { (first, second) } else { (second, first) };
+
+                server.disconnect(gone).await;
+                let (status, before) = server.message(&mut bob, "alice").await;
+                assert_eq!(status, DeliveryStatus::Delivered);
+                assert_eq!(presence(&before, "alice"), vec![true]);
+                server.disconnect(staying).await;
+                let (status, before) = server.message(&mut bob, "alice").await;
+                assert_eq!(status, DeliveryStatus::Queued);
+                assert_eq!(presence(&before, "alice"), vec![false]);
+            }
+        });
+    }
+
+    #[test]
+    fn revoked_sessions_are_gone_before_their_connections_end() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::Multi).await;
+            let mut bob = server.login("bob").await.unwrap();
+            let mut first = server.login("alice").await.unwrap();
+            let mut second = server.login("alice").await.unwrap();
+
+            let revoke = |current: &Connection, revoke: &Connection| Event::Sessions { from: "alice".to_string(), current: current.login.clone(), revoke: Some(revoke.login.clone()) };
+            server.broker.send(revoke(&second, &first)).await.unwrap();
+            rest(&mut first).await;
+            server.disconnect(first).await;
+            assert_eq!(server.message(&mut bob, "alice").await.0, DeliveryStatus::Delivered);
+
+            //revoking the last one takes the account offline right away, not again when it ends
+            server.broker.send(revoke(&second, &second)).await.unwrap();
+            rest(&mut second).await;
+            let (status, before) = server.message(&mut bob, "alice").await;
+            assert_eq!(status, DeliveryStatus::Queued);
+            assert_eq!(presence(&before, "alice"), vec![false]);
+            server.disconnect(second).await;
+            let (_, before) = server.message(&mut bob, "alice").await;
+            assert!(presence(&before, "alice").is_empty());
+        });
+    }
+
+    #[test]
+    fn unknown_and_repeated_disconnects_are_ignored() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::KickOld).await;
+            let mut bob = server.login("bob").await.unwrap();
+            let alice = server.login("alice").await.unwrap();
+            let id = alice.sender.id();
+            server.disconnect(alice).await;
+            for (name, id) in [("alice", id), ("nobody", id + 100)] {
+                let (_, pending) = server.queues.open(name);
+                server.broker.send(Event::Disconnect { name: name.to_string(), id, pending }).await.unwrap();
+            }
+
+            let (status, before) = server.message(&mut bob, "alice").await;
+            assert_eq!(status, DeliveryStatus::Queued);
+            assert_eq!(presence(&before, "alice"), vec![true, false]);
+        });
+    }
+
+    #[test]
+    fn logins_gone_before_they_are_accepted_change_nothing() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::KickOld).await;
+            let mut bob = server.login("bob").await.unwrap();
+            let mut alice = server.login("alice").await.unwrap();
+            let (sender, _receiver) = server.queues.open("alice");
+            let (_, session) = server.tokens.issue("alice").await.unwrap();
+            let (accepted, answer) = oneshot::channel();
+            drop(answer);
+            let event = Event::NewPeer { name: "alice".to_string(), id: sender.id(), session, token: None, addr: "test".to_string(), sender, accepted };
+            server.broker.send(event).await.unwrap();
+
+            //the kick never happened, alice still gets her messages
+            assert_eq!(server.message(&mut bob, "alice").await.0, DeliveryStatus::Delivered);
+            assert_eq!(server.message(&mut alice, "bob").await.0, DeliveryStatus::Delivered);
+        });
+    }
+
+    #[test]
+    fn broker_that_fails_to_start_is_started_again() {
+        task::block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("rooms.jsonl"), "not a room\n").unwrap();
+            let mut server = start(dir, DuplicateLogin::KickOld, Overflow::Disconnect).await;
+            task::sleep(Duration::from_millis(100)).await;
+            //the login waits in the channel until the next start works
+            std::fs::write(server.dir.path().join("rooms.jsonl"), "").unwrap();
+            let mut bob = server.login("bob").await.unwrap();
+            assert_eq!(server.message(&mut bob, "alice").await.0, DeliveryStatus::Queued);
+        });
+    }
+
+    #[test]
+    fn shutdown_tells_everyone_and_ends_with_the_last_connection() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::Multi).await;
+            let mut connections = vec![server.login("bob").await.unwrap(), server.login("alice").await.unwrap(), server.login("alice").await.unwrap()];
+            server.broker.send(Event::Shutdown).await.unwrap();
+            for connection in &mut connections {
+                assert_eq!(rest(connection).await.last(), Some(&shutdown_notice()));
+            }
+            assert!(server.login("carol").await.is_none());
+
+            for connection in connections {
+                server.disconnect(connection).await;
+            }
+            timeout(Duration::from_secs(5), server.handle).await.expect("the broker did not end");
+        });
+    }
+
+    #[test]
+    fn shutdown_now_keeps_what_stuck_writers_did_not_send() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::KickOld).await;
+            let mut bob = server.login("bob").await.unwrap();
+            //nobody takes anything off alice's queue, like a writer stuck on a client that does not read
+            let _alice = server.login("alice").await.unwrap();
+            assert_eq!(server.message(&mut bob, "alice").await.0, DeliveryStatus::Delivered);
+            server.broker.send(Event::Shutdown).await.unwrap();
+            rest(&mut bob).await;
+            server.disconnect(bob).await;
+
+            let (done, finished) = oneshot::channel();
+            server.broker.send(Event::ShutdownNow { done }).await.unwrap();
+            finished.await.unwrap();
+            let queued: Vec<String> = server.offline.take("alice").unwrap().into_iter().map(|msg| msg.content).collect();
+            assert_eq!(queued, vec!["ping"]);
+            timeout(Duration::from_secs(5), server.handle).await.expect("the broker did not end");
+        });
+    }
+
+    //Offers a file of `chunks` chunks from alice to bob and has bob accept it, returns what alice may send
+    async fn offer_file(server: &mut Server, alice: &mut Connection, bob: &mut Connection, chunks: u64) -> u64 {
+        let size = chunks * CHUNK_SIZE as u64;
+        let event = Event::FileStart { from: "alice".to_string(), transfer: "t1".to_string(), to: vec!["bob".to_string()], filename: "big.bin".to_string(), size, sha256: "00".to_string(), id: None };
+        server.broker.send(event).await.unwrap();
+        while !matches!(next(bob).await, Some(ServerEvent::FileOffer { .. })) {}
+        server.broker.send(Event::FileResume { from: "bob".to_string(), sender: "alice".to_string(), transfer: "t1".to_string(), offset: 0 }).await.unwrap();
+        loop {
+            if let Some(ServerEvent::FileSeek { offset: 0, until, .. }) = next(alice).await {
+                return until;
+            }
+        }
+    }
+
+    fn chunk(offset: u64) -> Event {
+        Event::FileChunk { from: "alice".to_string(), transfer: "t1".to_string(), offset, data: vec![0; CHUNK_SIZE] }
+    }
+
+    #[test]
+    fn stalled_recipient_holds_a_window_of_chunks_at_most() {
+        task::block_on(async {
+            let mut server = server(DuplicateLogin::KickOld).await;
+            let mut bob = server.login("bob").await.unwrap();
+            let mut alice = server.login("alice").await.unwrap();
+            let until = offer_file(&mut server, &mut alice, &mut bob, 100).await;
+            assert_eq!(until, transfers::WINDOW);
+
+            //a sender that ignores the window gets errors instead of filling bob's queue
+            let depth = bob.sender.stats().depth;
+            for n in 0..40 {
+                server.broker.send(chunk(n * CHUNK_SIZE as u64)).await.unwrap();
+            }
+            //the message comes after all of them, bob got the window and the message
+            let (_, before) = server.message(&mut alice, "bob").await;
+            assert!(before.iter().any(|event| matches!(event, ServerEvent::Error(ProtocolError { kind: ErrorKind::InvalidChunk, .. }))));
+            assert_eq!(bob.sender.stats().depth - depth, (transfers::WINDOW / CHUNK_SIZE as u64) as usize + 1);
+
+            //acking lets alice go on by as much
+            server.broker.send(Event::FileAck { from: "bob".to_string(), sender: "alice".to_string(), transfer: "t1".to_string(), offset: 2 * CHUNK_SIZE as u64 }).await.unwrap();
+            loop {
+                if let Some(ServerEvent::FileGrant { until, .. }) = next(&mut alice).await {
+                    assert_eq!(until, transfers::WINDOW + 2 * CHUNK_SIZE as u64);
+                    break;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn large_transfer_to_a_slow_peer_stays_within_its_queue() {
+        task::block_on(async {
+            for overflow in [Overflow::Disconnect, Overflow::DropOldest] {
+                let mut server = start(tempfile::tempdir().unwrap(), DuplicateLogin::KickOld, overflow).await;
+                let mut bob = server.login("bob").await.unwrap();
+                let mut alice = server.login("alice").await.unwrap();
+                //far more chunks than bob's queue holds
+                let chunks = 200;
+                let size = chunks * CHUNK_SIZE as u64;
+                let mut until = offer_file(&mut server, &mut alice, &mut bob, chunks).await;
+
+                //alice sends what she is granted, like the client does
+                let mut broker = server.broker.clone();
+                let sender = task::spawn(async move {
+                    let mut sent = 0;
+                    loop {
+                        while sent < until {
+                            broker.send(chunk(sent)).await.unwrap();
+                            sent += CHUNK_SIZE as u64;
+                        }
+                        match next(&mut alice).await {
+                            Some(ServerEvent::FileGrant { until: granted, .. }) => until = granted,
+                            Some(ServerEvent::FileFinished { complete, .. }) => return complete,
+                            Some(event) => panic!("unexpected {:?}", event),
+                            None => panic!("alice was disconnected"),
+                        }
+                    }
+                });
+
+                //bob takes his time with every chunk
+                let mut received = 0;
+                while received < size {
+                    match next(&mut bob).await {
+                        Some(ServerEvent::FileChunk { offset, data, .. }) => {
+                            assert_eq!(offset, received);
+                            received += data.len() as u64;
+                            task::sleep(Duration::from_millis(1)).await;
+                            server.broker.send(Event::FileAck { from: "bob".to_string(), sender: "alice".to_string(), transfer: "t1".to_string(), offset: received }).await.unwrap();
+                        }
+                        Some(_) => (),
+                        None => panic!("bob was disconnected under {:?}", overflow),
+                    }
+                }
+                assert!(sender.await);
+                let stats = bob.sender.stats();
+                assert_eq!(stats.dropped, 0);
+                assert!(stats.peak <= (transfers::WINDOW / CHUNK_SIZE as u64) as usize + 2, "peak {} under {:?}", stats.peak, overflow);
+            }
+        });
+    }
+
+    //Runs the prompt dialog over a real socket with `input` typed in, returns the login and what the client got
+    async fn prompt(first: &str, input: &[u8], users: &UserStore, lockout: &Lockout, config: &Config) -> (Option<String>, Vec<ServerEvent>) {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        client.write_all(input).await.unwrap();
+        let mut lines = FrameReader::new(BufReader::new(&stream), config.max_message_bytes);
+        let queues = Queues::new(64, Overflow::Disconnect, dir.path()).unwrap();
+        let (sender, receiver) = queues.open("127.0.0.1:1");
+        let ip = "127.0.0.1".parse().unwrap();
+        let login = prompt_login(first.to_string(), &mut lines, &sender, ip, users, lockout, config).await.unwrap();
+        drop(sender);
+        (login, futures::StreamExt::collect(receiver).await)
+    }
+
+    #[test]
+    fn prompt_login_refuses_guesses_once_the_account_locked() {
+        task::block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let storage: Arc<dyn Storage> = Arc::new(FlatFileStorage::open(dir.path()).unwrap());
+            let users = UserStore::load(storage).unwrap();
+            users.register("alice", &password::hash("secret").unwrap()).await.unwrap();
+            //locks before the prompt runs out of attempts
+            let config = Config { lockout_threshold: 2, max_login_attempts: 3, ..Config::default() };
+            let audit = Arc::new(AuditLog::open(dir.path()).unwrap());
+            let lockout = Lockout::new(audit, config.lockout_threshold, config.ip_lockout_threshold, Duration::from_secs(60));
+
+            //the right password comes right after the lock
+            let (login, events) = prompt("y", b"alice\nwrong\nwrong\nsecret\n", &users, &lockout, &config).await;
+            assert_eq!(login, None);
+            assert!(matches!(events.last(), Some(ServerEvent::System(SystemNotice::Info { text })) if text.starts_with("Too many failed logins")));
+        });
+    }
+
+    #[test]
+    fn prompt_registration_needs_a_name() {
+        task::block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let storage: Arc<dyn Storage> = Arc::new(FlatFileStorage::open(dir.path()).unwrap());
+            let users = UserStore::load(storage).unwrap();
+            let config = Config::default();
+            let lockout = Lockout::new(Arc::new(AuditLog::open(dir.path()).unwrap()), 5, 5, Duration::from_secs(60));
+
+            let (login, _) = prompt("n", b"\ncarol\nsecret\n", &users, &lockout, &config).await;
+            assert_eq!(login.as_deref(), Some("carol"));
+            assert!(!users.contains("").await);
+        });
+    }
+}
@@ -0,0 +1,86 @@
+// Messages for users that are not connected.
+//
+// Direct messages to a known user who is offline, or still waiting in a writer
+// queue when the connection dropped, are stored here and handed back in order
+// on the next login. Each user has a cap on the number of queued messages and
+// anything older than `max_age` is dropped instead of delivered.
+
+use std::sync::Arc;
+
+use crate::storage::{self, Storage, StoredMessage};
+use crate::Result;
+
+pub struct OfflineQueue {
+    storage: Arc<dyn Storage>,
+    max_per_user: usize,
+    max_age: u64, //seconds
+}
+
+impl OfflineQueue {
+    pub fn new(storage: Arc<dyn Storage>, max_per_user: usize, max_age: u64) -> Self {
+        OfflineQueue {
+            storage,
+            max_per_user,
+            max_age,
+        }
+    }
+
+    fn cutoff(&self) -> u64 {
+        storage::now().saturating_sub(self.max_age)
+    }
+
+    //Queues a message, false if the recipient's queue is full.
+    //Blocking, call from `spawn_blocking`.
+    pub fn push(&self, msg: &StoredMessage) -> Result<bool> {
+        self.storage.expire_offline(&msg.to, self.cutoff())?;
+        if self.storage.count_offline(&msg.to)? >= self.max_per_user {
+            return Ok(false);
+        }
+        self.storage.push_offline(msg)?;
+        Ok(true)
+    }
+
+    //Removes and returns the queued messages that have not expired, oldest first.
+    //Blocking, call from `spawn_blocking`.
+    pub fn take(&self, recipient: &str) -> Result<Vec<StoredMessage>> {
+        self.storage.expire_offline(recipient, self.cutoff())?;
+        self.storage.take_offline(recipient)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FlatFileStorage;
+
+    #[test]
+    fn caps_and_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(FlatFileStorage::open(dir.path()).unwrap());
+        let queue = OfflineQueue::new(Arc::clone(&storage), 2, 60);
+
+        let mut old = StoredMessage::new("alice", "bob", "old");
+        old.sent_at -= 120;
+        storage.push_offline(&old).unwrap();
+
+        //the expired message does not count against the cap
+        assert!(queue
+            .push(&StoredMessage::new("alice", "bob", "one"))
+            .unwrap());
+        assert!(queue
+            .push(&StoredMessage::new("alice", "bob", "two"))
+            .unwrap());
+        assert!(!queue
+            .push(&StoredMessage::new("alice", "bob", "three"))
+            .unwrap());
+
+        let contents: Vec<String> = queue
+            .take("bob")
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, vec!["one", "two"]);
+        assert!(queue.take("bob").unwrap().is_empty());
+    }
+}
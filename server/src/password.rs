@@ -0,0 +1,50 @@
+// Password hashing for the user list.
+//
+// Passwords are stored as Argon2id PHC strings (`$argon2id$v=19$...`), which
+// carry their own salt and parameters. Hashing is deliberately slow, so these
+// functions must be called through `task::spawn_blocking` from async code.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+use crate::Result;
+
+//Hashes a password with a fresh random salt
+pub fn hash(pwd: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(pwd.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?;
+    Ok(hash.to_string())
+}
+
+//Checks a password against a stored hash, the comparison is constant time
+pub fn verify(pwd: &str, stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(pwd.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false, //never fall back to comparing plaintext
+    }
+}
+
+//True if the stored value is already a hash rather than a legacy plaintext password
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_only_the_right_password() {
+        let stored = hash("hunter2").unwrap();
+        assert!(is_hashed(&stored));
+        assert!(!stored.contains("hunter2"));
+        assert!(verify("hunter2", &stored));
+        assert!(!verify("hunter3", &stored));
+        assert!(!verify("hunter2", "hunter2"));
+    }
+}
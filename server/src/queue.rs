@@ -0,0 +1,586 @@
+// Bounded outgoing queue of one connection.
+//
+// The connection opens it before login and its writer task takes events off,
+// the connection itself pushes its replies and the broker, once the client is
+// logged in, everything else. Nobody waits on a push: when a writer falls `capacity` events behind, the
+// configured `Overflow` policy decides what happens, dropping the oldest event,
+// disconnecting the slow client, or appending to a spill file under
+// `<data_dir>/spill` that the writer reads back in order once it caught up.
+// Pushes only ever touch memory. The spill file is written and read by a
+// blocking job of its own, which keeps the file open while the client is behind
+// and does no I/O while holding the queue's lock. The file is removed with the
+// queue.
+//
+// Every queue counts its depth, the deepest it got and what was dropped or
+// spilled, for the admins' `Stats`.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use async_std::task;
+use chat_common::protocol::{self, ServerEvent};
+use futures::task::AtomicWaker;
+use futures::Stream;
+use log::{error, warn};
+
+use crate::config::Overflow;
+use crate::Result;
+
+//Creates the queues of new connections and numbers them
+pub struct Queues {
+    capacity: usize,
+    overflow: Overflow,
+    spill_dir: PathBuf,
+    next_id: AtomicU64,
+}
+
+pub struct PeerSender {
+    shared: Arc<Shared>,
+}
+
+pub struct PeerReceiver {
+    shared: Arc<Shared>,
+}
+
+//The queue is closed, its client is gone or was too slow
+#[derive(Debug, PartialEq)]
+pub struct Closed;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueStats {
+    pub depth: usize, //waiting in memory and spilled
+    pub peak: usize,
+    pub dropped: u64,
+    pub spilled: u64,
+}
+
+struct Shared {
+    id: u64,
+    capacity: usize,
+    overflow: Overflow,
+    spill_path: PathBuf,
+    spill: Mutex<SpillFile>, //only for the spill job and `drain`, locked before `state`
+    state: Mutex<State>,
+    waker: AtomicWaker,
+}
+
+struct State {
+    name: String, //for the logs, the address until the client logs in
+    events: VecDeque<ServerEvent>,
+    spilled: usize, //events in the spill file not read back yet, or being written there
+    to_spill: Vec<ServerEvent>, //newer than everything spilled, waiting for the spill job
+    spilling: bool, //the spill job runs
+    senders: usize,
+    closed: bool, //nothing more is pushed, the writer ends once the rest is written
+    stopped: bool, //the writer ends right away, the rest stays for `drain`
+    receiver_gone: bool,
+    stats: QueueStats,
+}
+
+impl State {
+    fn update_depth(&mut self) {
+        self.stats.depth = self.events.len() + self.spilled + self.to_spill.len();
+        self.stats.peak = self.stats.peak.max(self.stats.depth);
+    }
+}
+
+//Opened on the first spill and kept open, emptied whenever everything was read back
+#[derive(Default)]
+struct SpillFile {
+    file: Option<File>,
+    read_at: u64, //offset of the first event not read back yet
+}
+
+impl SpillFile {
+    fn append(&mut self, path: &Path, events: &[ServerEvent]) -> Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(OpenOptions::new().create(true).truncate(true).read(true).write(true).open(path)?),
+        };
+        let mut lines = String::new();
+        for event in events {
+            lines.push_str(&protocol::encode(event)?);
+        }
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(lines.as_bytes())?;
+        Ok(())
+    }
+
+    //Reads back up to `max` events, oldest first
+    fn read(&mut self, max: usize) -> Result<Vec<ServerEvent>> {
+        let file = self.file.as_mut().ok_or("spill file is not open")?;
+        file.seek(SeekFrom::Start(self.read_at))?;
+        let mut reader = BufReader::new(file);
+        let mut events = Vec::new();
+        let mut line = String::new();
+        while events.len() < max {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                return Err("spill file ended early".into());
+            }
+            self.read_at += read as u64;
+            events.push(protocol::decode(&line)?);
+        }
+        Ok(events)
+    }
+
+    fn reset(&mut self) {
+        self.read_at = 0;
+        if let Some(file) = &self.file {
+            if let Err(e) = file.set_len(0) {
+                //start over with a new file rather than read stale events
+                error!("Failed to empty a spill file: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+impl Queues {
+    //Stale spill files of an earlier run are removed
+    pub fn new(capacity: usize, overflow: Overflow, data_dir: &Path) -> Result<Queues> {
+        let spill_dir = data_dir.join("spill");
+        if overflow == Overflow::Spill {
+            if spill_dir.exists() {
+                fs::remove_dir_all(&spill_dir)?;
+            }
+            fs::create_dir_all(&spill_dir)?;
+        }
+        Ok(Queues {
+            capacity,
+            overflow,
+            spill_dir,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    //The queue of a new connection, `name` shows up in the logs
+    pub fn open(&self, name: &str) -> (PeerSender, PeerReceiver) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let shared = Arc::new(Shared {
+            id,
+            capacity: self.capacity,
+            overflow: self.overflow,
+            spill_path: self.spill_dir.join(format!("{}.jsonl", id)),
+            spill: Mutex::new(SpillFile::default()),
+            state: Mutex::new(State {
+                name: name.to_string(),
+                events: VecDeque::new(),
+                spilled: 0,
+                to_spill: Vec::new(),
+                spilling: false,
+                senders: 1,
+                closed: false,
+                stopped: false,
+                receiver_gone: false,
+                stats: QueueStats::default(),
+            }),
+            waker: AtomicWaker::new(),
+        });
+        (
+            PeerSender {
+                shared: Arc::clone(&shared),
+            },
+            PeerReceiver { shared },
+        )
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_spill(&self) -> MutexGuard<'_, SpillFile> {
+        self.spill.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //Starts the spill job unless it runs already
+    fn kick(self: &Arc<Self>, state: &mut State) {
+        if !state.spilling {
+            state.spilling = true;
+            let shared = Arc::clone(self);
+            task::spawn_blocking(move || shared.spill_job());
+        }
+    }
+
+    //Writes what waits to be spilled and reads events back once the writer ran
+    //dry, until there is nothing left to do
+    fn spill_job(&self) {
+        loop {
+            let mut spill = self.lock_spill();
+            let mut state = self.lock();
+            if state.stopped {
+                //the rest is for `drain`
+            } else if !state.to_spill.is_empty() {
+                let batch = mem::take(&mut state.to_spill);
+                state.spilled += batch.len();
+                let name = state.name.clone();
+                drop(state);
+                if let Err(e) = spill.append(&self.spill_path, &batch) {
+                    error!("Failed to spill the queue of {}, disconnecting: {}", name, e);
+                    let mut state = self.lock();
+                    state.spilled -= batch.len();
+                    state.stats.dropped += batch.len() as u64;
+                    state.spilling = false;
+                    disconnect(self, state);
+                    return;
+                }
+                continue;
+            } else if state.events.is_empty() && state.spilled > 0 {
+                let want = state.spilled.min(self.capacity);
+                drop(state);
+                let read = spill.read(want);
+                let mut state = self.lock();
+                match read {
+                    Ok(events) => {
+                        state.spilled -= events.len();
+                        state.events.extend(events);
+                    }
+                    Err(e) => {
+                        error!("Lost {} spilled events of {}: {}", state.spilled, state.name, e);
+                        state.stats.dropped += state.spilled as u64;
+                        state.spilled = 0;
+                    }
+                }
+                state.update_depth();
+                //all read back, the file starts over
+                let empty = state.spilled == 0;
+                drop(state);
+                if empty {
+                    spill.reset();
+                }
+                self.waker.wake();
+                continue;
+            }
+            state.spilling = false;
+            return;
+        }
+    }
+
+    //The next event for the writer. With nothing spilled the events waiting to
+    //be spilled come back to memory right away, otherwise the spill job refills it.
+    fn next(self: &Arc<Self>, state: &mut State) -> Option<ServerEvent> {
+        if state.events.is_empty() {
+            if state.spilled > 0 {
+                self.kick(state);
+            } else if !state.to_spill.is_empty() {
+                let room = self.capacity.min(state.to_spill.len());
+                state.events.extend(state.to_spill.drain(..room));
+            }
+        }
+        let event = state.events.pop_front()?;
+        state.update_depth();
+        Some(event)
+    }
+
+    //Takes everything not sent yet, reading the spill file back when needed
+    async fn drain(self: &Arc<Self>) -> Vec<ServerEvent> {
+        {
+            let mut state = self.lock();
+            if state.spilled == 0 {
+                let mut events: Vec<ServerEvent> = state.events.drain(..).collect();
+                events.append(&mut state.to_spill);
+                state.update_depth();
+                return events;
+            }
+        }
+        let shared = Arc::clone(self);
+        task::spawn_blocking(move || shared.drain_spilled()).await
+    }
+
+    fn drain_spilled(&self) -> Vec<ServerEvent> {
+        let mut spill = self.lock_spill();
+        let mut state = self.lock();
+        let mut events: Vec<ServerEvent> = state.events.drain(..).collect();
+        let spilled = mem::take(&mut state.spilled);
+        let to_spill = mem::take(&mut state.to_spill);
+        state.update_depth();
+        drop(state);
+        match spill.read(spilled) {
+            Ok(read) => events.extend(read),
+            Err(e) => {
+                let mut state = self.lock();
+                error!("Lost {} spilled events of {}: {}", spilled, state.name, e);
+                state.stats.dropped += spilled as u64;
+            }
+        }
+        spill.reset();
+        events.extend(to_spill);
+        events
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if self.overflow == Overflow::Spill {
+            let _ = fs::remove_file(&self.spill_path);
+        }
+    }
+}
+
+impl PeerSender {
+    //Queues an event, never waits. What happens on a full queue is up to the
+    //overflow policy, an error means the event will not reach the client.
+    pub fn push(&self, event: ServerEvent) -> std::result::Result<(), Closed> {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        if state.closed || state.stopped || state.receiver_gone {
+            return Err(Closed);
+        }
+        //once something is spilled everything after it goes there too, to keep the order
+        if state.spilled > 0 || !state.to_spill.is_empty() || state.events.len() >= shared.capacity {
+            match shared.overflow {
+                Overflow::DropOldest => {
+                    if state.stats.dropped == 0 {
+                        warn!("Queue of {} is full, dropping its oldest events", state.name);
+                    }
+                    state.events.pop_front();
+                    state.stats.dropped += 1;
+                }
+                Overflow::Spill => {
+                    if state.stats.spilled == 0 {
+                        warn!("Queue of {} is full, spilling to {}", state.name, shared.spill_path.display());
+                    }
+                    state.to_spill.push(event);
+                    state.stats.spilled += 1;
+                    state.update_depth();
+                    shared.kick(&mut state);
+                    drop(state);
+                    shared.waker.wake();
+                    return Ok(());
+                }
+                Overflow::Disconnect => {
+                    warn!("Queue of {} is full, disconnecting the slow client", state.name);
+                    return Err(disconnect(shared, state));
+                }
+            }
+        }
+        state.events.push_back(event);
+        state.update_depth();
+        drop(state);
+        shared.waker.wake();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.shared.lock().stats
+    }
+
+    //Numbers connections, unique for the lifetime of the server
+    pub fn id(&self) -> u64 {
+        self.shared.id
+    }
+
+    pub fn set_name(&self, name: &str) {
+        self.shared.lock().name = name.to_string();
+    }
+
+    //Ends the connection once what is queued was written, for every clone
+    pub fn close(&self) {
+        self.shared.lock().closed = true;
+        self.shared.waker.wake();
+    }
+
+    //Ends the writer without writing the rest, which stays for `PeerReceiver::drain`
+    pub fn stop(&self) {
+        disconnect(&self.shared, self.shared.lock());
+    }
+
+    //Stops the writer and takes the rest itself, for a writer stuck on a client
+    //that does not read and so never gets to hand its receiver back
+    pub async fn abandon(&self) -> Vec<ServerEvent> {
+        self.stop();
+        self.shared.drain().await
+    }
+}
+
+fn disconnect(shared: &Shared, mut state: MutexGuard<'_, State>) -> Closed {
+    state.stopped = true;
+    drop(state);
+    shared.waker.wake();
+    Closed
+}
+
+impl Clone for PeerSender {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        PeerSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+//The last sender gone closes the queue
+impl Drop for PeerSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.closed = true;
+            drop(state);
+            self.shared.waker.wake();
+        }
+    }
+}
+
+impl PeerReceiver {
+    //Everything not sent yet, spilled events included
+    pub async fn drain(&mut self) -> Vec<ServerEvent> {
+        self.shared.drain().await
+    }
+}
+
+impl Drop for PeerReceiver {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_gone = true;
+    }
+}
+
+//Ends once the queue is closed and everything was taken, or right away when it
+//was stopped or the client was disconnected for being too slow
+impl Stream for PeerReceiver {
+    type Item = ServerEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerEvent>> {
+        let shared = &self.shared;
+        shared.waker.register(cx.waker());
+        let mut state = shared.lock();
+        if state.stopped {
+            return Poll::Ready(None);
+        }
+        match shared.next(&mut state) {
+            Some(event) => Poll::Ready(Some(event)),
+            //the spill job wakes the writer once it read something back
+            None if state.closed && state.spilled == 0 => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use futures::StreamExt;
+
+    fn info(n: usize) -> ServerEvent {
+        ServerEvent::info(n.to_string())
+    }
+
+    fn infos(range: std::ops::Range<usize>) -> Vec<ServerEvent> {
+        range.map(info).collect()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let queues = Queues::new(3, Overflow::DropOldest, dir.path()).unwrap();
+        let (sender, mut receiver) = queues.open("bob");
+        for n in 0..5 {
+            sender.push(info(n)).unwrap();
+        }
+        assert_eq!(sender.stats(), QueueStats { depth: 3, peak: 3, dropped: 2, spilled: 0 });
+        drop(sender);
+        let events: Vec<ServerEvent> = task::block_on(receiver.by_ref().collect());
+        assert_eq!(events, infos(2..5));
+    }
+
+    #[test]
+    fn disconnect_closes_and_keeps_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let queues = Queues::new(2, Overflow::Disconnect, dir.path()).unwrap();
+        let (sender, mut receiver) = queues.open("bob");
+        sender.push(info(0)).unwrap();
+        sender.push(info(1)).unwrap();
+        assert_eq!(sender.push(info(2)), Err(Closed));
+        assert_eq!(sender.push(info(3)), Err(Closed));
+        assert_eq!(task::block_on(receiver.next()), None);
+        assert_eq!(task::block_on(receiver.drain()), infos(0..2));
+    }
+
+    //Waits for the spill job to write `lines` events
+    fn wait_for_spill(path: &Path, lines: usize) {
+        for _ in 0..500 {
+            if fs::read_to_string(path).map(|text| text.lines().count()).unwrap_or(0) == lines {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("{} events were not spilled", lines);
+    }
+
+    #[test]
+    fn spilled_events_come_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let queues = Queues::new(2, Overflow::Spill, dir.path()).unwrap();
+        let (sender, mut receiver) = queues.open("bob");
+        for n in 0..5 {
+            sender.push(info(n)).unwrap();
+        }
+        assert_eq!(sender.stats(), QueueStats { depth: 5, peak: 5, dropped: 0, spilled: 3 });
+        let spill = dir.path().join("spill").join(format!("{}.jsonl", sender.id()));
+        wait_for_spill(&spill, 3);
+
+        //taking some makes room, but new events still queue behind the spilled ones
+        let first: Vec<ServerEvent> = task::block_on(receiver.by_ref().take(3).collect());
+        assert_eq!(first, infos(0..3));
+        sender.push(info(5)).unwrap();
+        assert_eq!(task::block_on(receiver.drain()), infos(3..6));
+        assert_eq!(fs::metadata(&spill).unwrap().len(), 0);
+
+        sender.push(info(6)).unwrap();
+        drop(sender);
+        let rest: Vec<ServerEvent> = task::block_on(receiver.collect());
+        assert_eq!(rest, infos(6..7));
+    }
+
+    #[test]
+    fn writer_reads_everything_spilled_before_it_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let queues = Queues::new(4, Overflow::Spill, dir.path()).unwrap();
+        let (sender, receiver) = queues.open("bob");
+        for n in 0..50 {
+            sender.push(info(n)).unwrap();
+        }
+        drop(sender);
+        let events: Vec<ServerEvent> = task::block_on(receiver.collect());
+        assert_eq!(events, infos(0..50));
+    }
+
+    #[test]
+    fn close_writes_the_rest_and_stop_does_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let queues = Queues::new(10, Overflow::Disconnect, dir.path()).unwrap();
+        let (sender, mut receiver) = queues.open("127.0.0.1:1");
+        let broker = sender.clone();
+        sender.push(info(0)).unwrap();
+        broker.close();
+        assert_eq!(sender.push(info(1)), Err(Closed));
+        //a clone is still around, closing ends the queue anyway
+        let events: Vec<ServerEvent> = task::block_on(receiver.by_ref().collect());
+        assert_eq!(events, infos(0..1));
+
+        let (sender, mut receiver) = queues.open("127.0.0.1:2");
+        assert_ne!(sender.id(), broker.id());
+        sender.push(info(0)).unwrap();
+        sender.stop();
+        assert_eq!(task::block_on(receiver.next()), None);
+        assert_eq!(task::block_on(receiver.drain()), infos(0..1));
+
+        //the broker can take the rest itself when the writer does not come back
+        let (sender, mut receiver) = queues.open("127.0.0.1:3");
+        sender.push(info(0)).unwrap();
+        sender.push(info(1)).unwrap();
+        assert_eq!(task::block_on(sender.abandon()), infos(0..2));
+        assert_eq!(task::block_on(receiver.next()), None);
+    }
+}
@@ -0,0 +1,209 @@
+// Named chat rooms.
+//
+// Owned by the broker, so no locking is needed. Every change returns the updated
+// record and the broker writes it to storage, that way rooms, their topic and
+// their members survive a restart.
+
+use std::collections::HashMap;
+
+use chat_common::protocol::{ErrorKind, Member, RoomInfo, ServerEvent};
+
+use crate::storage::{self, RoomRecord, Storage};
+use crate::Result;
+
+//Room related requests, forwarded from the connection to the broker
+pub enum RoomOp {
+    Create { room: String, topic: Option<String> },
+    Join { room: String },
+    Leave { room: String },
+    List,
+    Members { room: String },
+    History { room: String, since: u64 },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RoomError {
+    InvalidName,
+    NoSuchRoom,
+    RoomExists,
+    NotMember,
+}
+
+impl RoomError {
+    pub fn to_event(&self, room: &str) -> ServerEvent {
+        match self {
+            RoomError::InvalidName => ServerEvent::error(
+                ErrorKind::InvalidName,
+                format!(
+                    "`{}` is not a valid room name, use # followed by letters, digits, - or _",
+                    room
+                ),
+            ),
+            RoomError::NoSuchRoom => {
+                ServerEvent::error(ErrorKind::NoSuchRoom, format!("There is no room {}", room))
+            }
+            RoomError::RoomExists => {
+                ServerEvent::error(ErrorKind::RoomExists, format!("{} already exists", room))
+            }
+            RoomError::NotMember => ServerEvent::error(
+                ErrorKind::NotMember,
+                format!("You are not a member of {}", room),
+            ),
+        }
+    }
+}
+
+//True if a message destination names a room rather than a user
+pub fn is_room(addr: &str) -> bool {
+    addr.starts_with('#')
+}
+
+//Canonical `#name` form of a room name, the leading # is optional on input
+pub fn normalize(room: &str) -> std::result::Result<String, RoomError> {
+    let name = room.trim().trim_start_matches('#').to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(format!("#{}", name))
+    } else {
+        Err(RoomError::InvalidName)
+    }
+}
+
+pub struct Rooms {
+    rooms: HashMap<String, RoomRecord>,
+}
+
+impl Rooms {
+    pub fn load(storage: &dyn Storage) -> Result<Rooms> {
+        let rooms = storage
+            .load_rooms()?
+            .into_iter()
+            .map(|room| (room.name.clone(), room))
+            .collect();
+        Ok(Rooms { rooms })
+    }
+
+    pub fn get(&self, room: &str) -> std::result::Result<&RoomRecord, RoomError> {
+        self.rooms.get(room).ok_or(RoomError::NoSuchRoom)
+    }
+
+    //Creates a room with its creator as the only member
+    pub fn create(
+        &mut self,
+        room: &str,
+        topic: Option<String>,
+        creator: &str,
+    ) -> std::result::Result<&RoomRecord, RoomError> {
+        if self.rooms.contains_key(room) {
+            return Err(RoomError::RoomExists);
+        }
+        let record = RoomRecord {
+            name: room.to_string(),
+            topic,
+            creator: creator.to_string(),
+            created_at: storage::now(),
+            members: vec![creator.to_string()],
+        };
+        Ok(self.rooms.entry(room.to_string()).or_insert(record))
+    }
+
+    //Adds a member, joining twice is not an error
+    pub fn join(&mut self, room: &str, user: &str) -> std::result::Result<&RoomRecord, RoomError> {
+        let record = self.rooms.get_mut(room).ok_or(RoomError::NoSuchRoom)?;
+        if !record.members.iter().any(|m| m == user) {
+            record.members.push(user.to_string());
+        }
+        Ok(record)
+    }
+
+    pub fn leave(&mut self, room: &str, user: &str) -> std::result::Result<&RoomRecord, RoomError> {
+        let record = self.rooms.get_mut(room).ok_or(RoomError::NoSuchRoom)?;
+        let before = record.members.len();
+        record.members.retain(|m| m != user);
+        if record.members.len() == before {
+            return Err(RoomError::NotMember);
+        }
+        Ok(record)
+    }
+
+    //Members of a room the user belongs to
+    pub fn members_of(&self, room: &str, user: &str) -> std::result::Result<&[String], RoomError> {
+        let record = self.get(room)?;
+        if !record.members.iter().any(|m| m == user) {
+            return Err(RoomError::NotMember);
+        }
+        Ok(&record.members)
+    }
+
+    //Every room, marking the ones `user` is a member of
+    pub fn list(&self, user: &str) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .values()
+            .map(|room| RoomInfo {
+                name: room.name.clone(),
+                topic: room.topic.clone(),
+                creator: room.creator.clone(),
+                members: room.members.len(),
+                joined: room.members.iter().any(|m| m == user),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    pub fn members(
+        &self,
+        room: &str,
+        online: impl Fn(&str) -> bool,
+    ) -> std::result::Result<Vec<Member>, RoomError> {
+        Ok(self
+            .get(room)?
+            .members
+            .iter()
+            .map(|name| Member {
+                name: name.clone(),
+                online: online(name),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_join_leave() {
+        let mut rooms = Rooms {
+            rooms: HashMap::new(),
+        };
+        assert_eq!(normalize(" Rust"), Ok("#rust".to_string()));
+        assert_eq!(normalize("#a/b"), Err(RoomError::InvalidName));
+
+        rooms.create("#rust", None, "alice").unwrap();
+        assert_eq!(
+            rooms.create("#rust", None, "bob").unwrap_err(),
+            RoomError::RoomExists
+        );
+        assert_eq!(
+            rooms.members_of("#rust", "bob").unwrap_err(),
+            RoomError::NotMember
+        );
+
+        rooms.join("#rust", "bob").unwrap();
+        rooms.join("#rust", "bob").unwrap();
+        assert_eq!(rooms.members_of("#rust", "bob").unwrap(), ["alice", "bob"]);
+
+        rooms.leave("#rust", "alice").unwrap();
+        assert_eq!(
+            rooms.leave("#rust", "alice").unwrap_err(),
+            RoomError::NotMember
+        );
+        assert_eq!(rooms.join("#go", "bob").unwrap_err(), RoomError::NoSuchRoom);
+    }
+}
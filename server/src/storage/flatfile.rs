@@ -0,0 +1,274 @@
+// Flat-file backend, the original `userlist.txt` layout plus one JSON-lines file
+// per recipient for offline messages and history:
+//
+//   <data_dir>/userlist.txt          name:hash per line
+//   <data_dir>/offline/<name>.jsonl  queued messages, removed once delivered
+//   <data_dir>/history/<name>.jsonl  delivered messages, append only
+//   <data_dir>/rooms.jsonl           one room per line, rewritten on every change
+//   <data_dir>/blocks.jsonl          one block per line, rewritten on every change
+//   <data_dir>/sessions.jsonl        one session per line, rewritten on every change
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{Account, BlockRecord, RoomRecord, SessionRecord, Storage, StoredMessage};
+use crate::Result;
+
+pub struct FlatFileStorage {
+    dir: PathBuf,
+    lock: Mutex<()>, //serializes read-modify-write sequences on the files
+}
+
+impl FlatFileStorage {
+    pub fn open(dir: &Path) -> Result<FlatFileStorage> {
+        fs::create_dir_all(dir.join("offline"))?;
+        fs::create_dir_all(dir.join("history"))?;
+        Ok(FlatFileStorage {
+            dir: dir.to_path_buf(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn userlist(&self) -> PathBuf {
+        self.dir.join("userlist.txt")
+    }
+
+    //Recipient names come from clients, so anything but [a-z0-9] is escaped
+    //before it becomes part of a path
+    fn message_file(&self, kind: &str, name: &str) -> PathBuf {
+        let mut file_name = String::new();
+        for c in name.chars() {
+            if c.is_ascii_lowercase() || c.is_ascii_digit() {
+                file_name.push(c);
+            } else {
+                for b in c.to_string().bytes() {
+                    file_name.push_str(&format!("_{:02x}", b));
+                }
+            }
+        }
+        self.dir.join(kind).join(file_name + ".jsonl")
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn read_optional(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+//Writes to a temporary file first and renames it over the target,
+//so an interrupted write leaves the old file intact
+fn replace(path: &Path, content: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn append(path: &Path, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn read_lines<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let mut items = Vec::new();
+    for line in read_optional(path)?.lines() {
+        if !line.trim().is_empty() {
+            items.push(serde_json::from_str(line)?);
+        }
+    }
+    Ok(items)
+}
+
+fn write_lines<T: serde::Serialize>(path: &Path, items: &[T]) -> Result<()> {
+    let mut content = String::new();
+    for item in items {
+        content.push_str(&serde_json::to_string(item)?);
+        content.push('\n');
+    }
+    replace(path, &content)
+}
+
+fn encode_message(msg: &StoredMessage) -> Result<String> {
+    let mut line = serde_json::to_string(msg)?;
+    line.push('\n');
+    Ok(line)
+}
+
+impl Storage for FlatFileStorage {
+    fn load_accounts(&self) -> Result<Vec<Account>> {
+        let _lock = self.lock();
+        let mut accounts = Vec::new();
+        for line in read_optional(&self.userlist())?.lines() {
+            if let Some(idx) = line.find(':') {
+                accounts.push(Account {
+                    name: line[..idx].to_string(),
+                    pwd_hash: line[idx + 1..].trim().to_string(),
+                });
+            }
+        }
+        Ok(accounts)
+    }
+
+    fn insert_account(&self, account: &Account) -> Result<()> {
+        let _lock = self.lock();
+        append(
+            &self.userlist(),
+            &format!("{}:{}\n", account.name, account.pwd_hash),
+        )
+    }
+
+    fn replace_accounts(&self, accounts: &[Account]) -> Result<()> {
+        let _lock = self.lock();
+        let mut content = String::new();
+        for account in accounts {
+            content.push_str(&format!("{}:{}\n", account.name, account.pwd_hash));
+        }
+        replace(&self.userlist(), &content)
+    }
+
+    fn push_offline(&self, msg: &StoredMessage) -> Result<()> {
+        let _lock = self.lock();
+        append(
+            &self.message_file("offline", &msg.to),
+            &encode_message(msg)?,
+        )
+    }
+
+    fn take_offline(&self, recipient: &str) -> Result<Vec<StoredMessage>> {
+        let _lock = self.lock();
+        let path = self.message_file("offline", recipient);
+        let messages = read_lines(&path)?;
+        if !messages.is_empty() {
+            fs::remove_file(&path)?;
+        }
+        Ok(messages)
+    }
+
+    fn count_offline(&self, recipient: &str) -> Result<usize> {
+        let _lock = self.lock();
+        Ok(read_lines::<StoredMessage>(&self.message_file("offline", recipient))?.len())
+    }
+
+    fn expire_offline(&self, recipient: &str, before: u64) -> Result<usize> {
+        let _lock = self.lock();
+        let path = self.message_file("offline", recipient);
+        let messages = read_lines::<StoredMessage>(&path)?;
+        let total = messages.len();
+        let kept: Vec<StoredMessage> = messages
+            .into_iter()
+            .filter(|msg| msg.sent_at >= before)
+            .collect();
+        if kept.len() == total {
+            return Ok(0);
+        }
+        if kept.is_empty() {
+            fs::remove_file(&path)?;
+        } else {
+            let mut content = String::new();
+            for msg in &kept {
+                content.push_str(&encode_message(msg)?);
+            }
+            replace(&path, &content)?;
+        }
+        Ok(total - kept.len())
+    }
+
+    fn append_history(&self, msg: &StoredMessage) -> Result<()> {
+        let _lock = self.lock();
+        append(
+            &self.message_file("history", &msg.to),
+            &encode_message(msg)?,
+        )
+    }
+
+    fn history(&self, to: &str, since: u64, limit: usize) -> Result<Vec<StoredMessage>> {
+        let _lock = self.lock();
+        let mut messages: Vec<StoredMessage> =
+            read_lines::<StoredMessage>(&self.message_file("history", to))?
+                .into_iter()
+                .filter(|msg| msg.sent_at > since)
+                .collect();
+        let skip = messages.len().saturating_sub(limit);
+        Ok(messages.split_off(skip))
+    }
+
+    fn load_rooms(&self) -> Result<Vec<RoomRecord>> {
+        let _lock = self.lock();
+        read_lines(&self.dir.join("rooms.jsonl"))
+    }
+
+    fn save_room(&self, room: &RoomRecord) -> Result<()> {
+        let _lock = self.lock();
+        let path = self.dir.join("rooms.jsonl");
+        let mut rooms: Vec<RoomRecord> = read_lines(&path)?;
+        match rooms.iter_mut().find(|r| r.name == room.name) {
+            Some(existing) => *existing = room.clone(),
+            None => rooms.push(room.clone()),
+        }
+        write_lines(&path, &rooms)
+    }
+
+    fn load_blocks(&self) -> Result<Vec<BlockRecord>> {
+        let _lock = self.lock();
+        read_lines(&self.dir.join("blocks.jsonl"))
+    }
+
+    fn add_block(&self, block: &BlockRecord) -> Result<()> {
+        let _lock = self.lock();
+        let path = self.dir.join("blocks.jsonl");
+        let mut blocks: Vec<BlockRecord> = read_lines(&path)?;
+        if blocks.contains(block) {
+            return Ok(());
+        }
+        blocks.push(block.clone());
+        write_lines(&path, &blocks)
+    }
+
+    fn remove_block(&self, block: &BlockRecord) -> Result<()> {
+        let _lock = self.lock();
+        let path = self.dir.join("blocks.jsonl");
+        let mut blocks: Vec<BlockRecord> = read_lines(&path)?;
+        let before = blocks.len();
+        blocks.retain(|b| b != block);
+        if blocks.len() == before {
+            return Ok(());
+        }
+        write_lines(&path, &blocks)
+    }
+
+    fn load_sessions(&self) -> Result<Vec<SessionRecord>> {
+        let _lock = self.lock();
+        read_lines(&self.dir.join("sessions.jsonl"))
+    }
+
+    fn add_session(&self, session: &SessionRecord) -> Result<()> {
+        let _lock = self.lock();
+        let mut line = serde_json::to_string(session)?;
+        line.push('\n');
+        append(&self.dir.join("sessions.jsonl"), &line)
+    }
+
+    fn remove_session(&self, id: &str) -> Result<()> {
+        let _lock = self.lock();
+        let path = self.dir.join("sessions.jsonl");
+        let mut sessions: Vec<SessionRecord> = read_lines(&path)?;
+        let before = sessions.len();
+        sessions.retain(|s| s.id != id);
+        if sessions.len() == before {
+            return Ok(());
+        }
+        write_lines(&path, &sessions)
+    }
+}
@@ -0,0 +1,386 @@
+// Persistence backends for accounts, offline messages, message history, rooms,
+// block lists and login sessions.
+//
+// The server only talks to `dyn Storage`, so a backend can be swapped without
+// touching the broker or the connection code. Every method is blocking (file or
+// database I/O), async callers go through `task::spawn_blocking`.
+//
+// A new backend has to pass the `conformance` tests at the bottom of this file.
+
+mod flatfile;
+mod sqlite;
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+pub use flatfile::FlatFileStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    pub pwd_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub from: String,
+    pub to: String, //a username, or a room for room messages
+    pub content: String,
+    pub sent_at: u64, //seconds since the unix epoch
+}
+
+//Persistent part of a room, online state is only kept by the broker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoomRecord {
+    pub name: String,
+    pub topic: Option<String>,
+    pub creator: String,
+    pub created_at: u64,
+    pub members: Vec<String>,
+}
+
+//`user` does not want messages from `blocked`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockRecord {
+    pub user: String,
+    pub blocked: String,
+}
+
+//A login session, only the digest of its token is kept
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionRecord {
+    pub id: String, //shown to the user, to tell sessions apart and revoke them
+    pub user: String,
+    pub token_hash: String, //hex SHA-256 of the token
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl StoredMessage {
+    pub fn new(from: &str, to: &str, content: &str) -> Self {
+        StoredMessage {
+            from: from.to_string(),
+            to: to.to_string(),
+            content: content.to_string(),
+            sent_at: now(),
+        }
+    }
+}
+
+pub trait Storage: Send + Sync {
+    //Every account, in no particular order
+    fn load_accounts(&self) -> Result<Vec<Account>>;
+    //Adds one account, the name must not exist yet
+    fn insert_account(&self, account: &Account) -> Result<()>;
+    //Atomically replaces all accounts, used for bulk changes like rehashing
+    fn replace_accounts(&self, accounts: &[Account]) -> Result<()>;
+
+    //Queues a message for a recipient that is not connected
+    fn push_offline(&self, msg: &StoredMessage) -> Result<()>;
+    //Removes and returns every queued message of a recipient, oldest first
+    fn take_offline(&self, recipient: &str) -> Result<Vec<StoredMessage>>;
+    //Number of queued messages of a recipient
+    fn count_offline(&self, recipient: &str) -> Result<usize>;
+    //Drops the queued messages of a recipient sent before `before`, returns how many
+    fn expire_offline(&self, recipient: &str, before: u64) -> Result<usize>;
+
+    //Records a delivered message
+    fn append_history(&self, msg: &StoredMessage) -> Result<()>;
+    //Up to `limit` of the newest messages sent to `to` after `since`, oldest first
+    fn history(&self, to: &str, since: u64, limit: usize) -> Result<Vec<StoredMessage>>;
+
+    //Every room, in no particular order
+    fn load_rooms(&self) -> Result<Vec<RoomRecord>>;
+    //Creates the room or replaces the stored one with the same name
+    fn save_room(&self, room: &RoomRecord) -> Result<()>;
+
+    //Every block, in no particular order
+    fn load_blocks(&self) -> Result<Vec<BlockRecord>>;
+    //Adding a block that exists already is not an error
+    fn add_block(&self, block: &BlockRecord) -> Result<()>;
+    //Removing a block that does not exist is not an error
+    fn remove_block(&self, block: &BlockRecord) -> Result<()>;
+
+    //Every session, expired ones included
+    fn load_sessions(&self) -> Result<Vec<SessionRecord>>;
+    fn add_session(&self, session: &SessionRecord) -> Result<()>;
+    //Removing a session that does not exist is not an error
+    fn remove_session(&self, id: &str) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    FlatFile,
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "flatfile" => Ok(Backend::FlatFile),
+            "sqlite" => Ok(Backend::Sqlite),
+            other => Err(format!(
+                "unknown storage backend `{}`, expected `flatfile` or `sqlite`",
+                other
+            )),
+        }
+    }
+}
+
+//Opens the selected backend inside `data_dir`
+pub fn open(backend: Backend, data_dir: &Path) -> Result<Arc<dyn Storage>> {
+    std::fs::create_dir_all(data_dir)?;
+    Ok(match backend {
+        Backend::FlatFile => Arc::new(FlatFileStorage::open(data_dir)?),
+        Backend::Sqlite => Arc::new(SqliteStorage::open(&data_dir.join("chat.db"))?),
+    })
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod conformance {
+    use super::*;
+
+    fn account(name: &str, pwd_hash: &str) -> Account {
+        Account {
+            name: name.to_string(),
+            pwd_hash: pwd_hash.to_string(),
+        }
+    }
+
+    fn message(from: &str, to: &str, content: &str, sent_at: u64) -> StoredMessage {
+        StoredMessage {
+            from: from.to_string(),
+            to: to.to_string(),
+            content: content.to_string(),
+            sent_at,
+        }
+    }
+
+    fn sorted(mut accounts: Vec<Account>) -> Vec<Account> {
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        accounts
+    }
+
+    fn accounts(storage: &dyn Storage) {
+        assert!(storage.load_accounts().unwrap().is_empty());
+
+        storage.insert_account(&account("alice", "h1")).unwrap();
+        storage.insert_account(&account("bob", "h2")).unwrap();
+        assert_eq!(
+            sorted(storage.load_accounts().unwrap()),
+            vec![account("alice", "h1"), account("bob", "h2")]
+        );
+
+        storage
+            .replace_accounts(&[account("alice", "h3"), account("carol", "h4")])
+            .unwrap();
+        assert_eq!(
+            sorted(storage.load_accounts().unwrap()),
+            vec![account("alice", "h3"), account("carol", "h4")]
+        );
+    }
+
+    fn offline(storage: &dyn Storage) {
+        assert!(storage.take_offline("bob").unwrap().is_empty());
+
+        storage
+            .push_offline(&message("alice", "bob", "first", 1))
+            .unwrap();
+        storage
+            .push_offline(&message("carol", "dave", "other", 2))
+            .unwrap();
+        storage
+            .push_offline(&message("alice", "bob", "second: with colon", 3))
+            .unwrap();
+
+        assert_eq!(
+            storage.take_offline("bob").unwrap(),
+            vec![
+                message("alice", "bob", "first", 1),
+                message("alice", "bob", "second: with colon", 3)
+            ]
+        );
+        assert!(storage.take_offline("bob").unwrap().is_empty());
+        assert_eq!(storage.take_offline("dave").unwrap().len(), 1);
+    }
+
+    fn offline_expiry(storage: &dyn Storage) {
+        for i in 1..=4 {
+            storage
+                .push_offline(&message("alice", "bob", &format!("m{}", i), i))
+                .unwrap();
+        }
+        storage
+            .push_offline(&message("alice", "carol", "old", 1))
+            .unwrap();
+        assert_eq!(storage.count_offline("bob").unwrap(), 4);
+        assert_eq!(storage.count_offline("dave").unwrap(), 0);
+
+        assert_eq!(storage.expire_offline("bob", 3).unwrap(), 2);
+        assert_eq!(storage.expire_offline("bob", 3).unwrap(), 0);
+        assert_eq!(storage.expire_offline("dave", 3).unwrap(), 0);
+        assert_eq!(storage.count_offline("bob").unwrap(), 2);
+        assert_eq!(storage.count_offline("carol").unwrap(), 1);
+
+        let left: Vec<String> = storage
+            .take_offline("bob")
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(left, vec!["m3", "m4"]);
+    }
+
+    fn history(storage: &dyn Storage) {
+        for i in 1..=5 {
+            storage
+                .append_history(&message("alice", "#rust", &format!("m{}", i), i))
+                .unwrap();
+        }
+        storage
+            .append_history(&message("bob", "alice", "dm", 3))
+            .unwrap();
+
+        let newest: Vec<String> = storage
+            .history("#rust", 0, 2)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(newest, vec!["m4", "m5"]);
+
+        assert_eq!(storage.history("#rust", 3, 10).unwrap().len(), 2);
+        assert_eq!(
+            storage.history("alice", 0, 10).unwrap(),
+            vec![message("bob", "alice", "dm", 3)]
+        );
+        assert!(storage.history("#empty", 0, 10).unwrap().is_empty());
+    }
+
+    fn rooms(storage: &dyn Storage) {
+        assert!(storage.load_rooms().unwrap().is_empty());
+
+        let mut room = RoomRecord {
+            name: "#rust".to_string(),
+            topic: Some("crabs".to_string()),
+            creator: "alice".to_string(),
+            created_at: 7,
+            members: vec!["alice".to_string()],
+        };
+        storage.save_room(&room).unwrap();
+        room.members.push("bob".to_string());
+        room.topic = None;
+        storage.save_room(&room).unwrap();
+
+        assert_eq!(storage.load_rooms().unwrap(), vec![room]);
+    }
+
+    fn blocks(storage: &dyn Storage) {
+        let block = |user: &str, blocked: &str| BlockRecord {
+            user: user.to_string(),
+            blocked: blocked.to_string(),
+        };
+        assert!(storage.load_blocks().unwrap().is_empty());
+
+        storage.add_block(&block("alice", "bob")).unwrap();
+        storage.add_block(&block("alice", "bob")).unwrap();
+        storage.add_block(&block("alice", "carol")).unwrap();
+        storage.remove_block(&block("alice", "carol")).unwrap();
+        storage.remove_block(&block("dave", "carol")).unwrap();
+        assert_eq!(storage.load_blocks().unwrap(), vec![block("alice", "bob")]);
+    }
+
+    fn sessions(storage: &dyn Storage) {
+        let session = |id: &str, user: &str| SessionRecord {
+            id: id.to_string(),
+            user: user.to_string(),
+            token_hash: format!("hash-{}", id),
+            created_at: 1,
+            expires_at: 2,
+        };
+        assert!(storage.load_sessions().unwrap().is_empty());
+
+        storage.add_session(&session("a1", "alice")).unwrap();
+        storage.add_session(&session("b1", "bob")).unwrap();
+        storage.remove_session("b1").unwrap();
+        storage.remove_session("zz").unwrap();
+        assert_eq!(
+            storage.load_sessions().unwrap(),
+            vec![session("a1", "alice")]
+        );
+    }
+
+    fn persistence(open: &dyn Fn() -> Box<dyn Storage>) {
+        {
+            let storage = open();
+            storage.insert_account(&account("alice", "h1")).unwrap();
+            storage
+                .push_offline(&message("bob", "alice", "hi", 1))
+                .unwrap();
+            storage
+                .append_history(&message("bob", "#room", "hey", 1))
+                .unwrap();
+            storage
+                .save_room(&RoomRecord {
+                    name: "#room".to_string(),
+                    topic: None,
+                    creator: "bob".to_string(),
+                    created_at: 1,
+                    members: vec!["bob".to_string()],
+                })
+                .unwrap();
+        }
+        let storage = open();
+        assert_eq!(
+            storage.load_accounts().unwrap(),
+            vec![account("alice", "h1")]
+        );
+        assert_eq!(storage.take_offline("alice").unwrap().len(), 1);
+        assert_eq!(storage.history("#room", 0, 10).unwrap().len(), 1);
+        assert_eq!(storage.load_rooms().unwrap().len(), 1);
+    }
+
+    //Every backend must pass all of these, each check starts from an empty store
+    fn run(open: &dyn Fn(&Path) -> Box<dyn Storage>) {
+        for check in [
+            accounts,
+            offline,
+            offline_expiry,
+            history,
+            rooms,
+            blocks,
+            sessions,
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            check(open(dir.path()).as_ref());
+        }
+        let dir = tempfile::tempdir().unwrap();
+        persistence(&|| open(dir.path()));
+    }
+
+    #[test]
+    fn flatfile_backend() {
+        run(&|dir| Box::new(FlatFileStorage::open(dir).unwrap()));
+    }
+
+    #[test]
+    fn sqlite_backend() {
+        run(&|dir| Box::new(SqliteStorage::open(&dir.join("chat.db")).unwrap()));
+    }
+}
@@ -0,0 +1,294 @@
+// Embedded SQLite backend, everything lives in a single `chat.db` file.
+
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{params, Connection};
+
+use super::{Account, BlockRecord, RoomRecord, SessionRecord, Storage, StoredMessage};
+use crate::Result;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        name     TEXT PRIMARY KEY,
+        pwd_hash TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS offline (
+        id        INTEGER PRIMARY KEY AUTOINCREMENT,
+        sender    TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        content   TEXT NOT NULL,
+        sent_at   INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS offline_recipient ON offline (recipient, id);
+    CREATE TABLE IF NOT EXISTS history (
+        id        INTEGER PRIMARY KEY AUTOINCREMENT,
+        sender    TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        content   TEXT NOT NULL,
+        sent_at   INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_recipient ON history (recipient, sent_at);
+    CREATE TABLE IF NOT EXISTS rooms (
+        name       TEXT PRIMARY KEY,
+        topic      TEXT,
+        creator    TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        members    TEXT NOT NULL -- JSON array of usernames
+    );
+    CREATE TABLE IF NOT EXISTS blocks (
+        user    TEXT NOT NULL,
+        blocked TEXT NOT NULL,
+        PRIMARY KEY (user, blocked)
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        id         TEXT PRIMARY KEY,
+        user       TEXT NOT NULL,
+        token_hash TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+";
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>, //a connection can't be shared between threads by itself
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn row_to_message(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredMessage> {
+    Ok(StoredMessage {
+        from: row.get(0)?,
+        to: row.get(1)?,
+        content: row.get(2)?,
+        sent_at: row.get::<_, i64>(3)? as u64,
+    })
+}
+
+impl Storage for SqliteStorage {
+    fn load_accounts(&self) -> Result<Vec<Account>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT name, pwd_hash FROM accounts")?;
+        let accounts = stmt
+            .query_map([], |row| {
+                Ok(Account {
+                    name: row.get(0)?,
+                    pwd_hash: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(accounts)
+    }
+
+    fn insert_account(&self, account: &Account) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO accounts (name, pwd_hash) VALUES (?1, ?2)",
+            params![account.name, account.pwd_hash],
+        )?;
+        Ok(())
+    }
+
+    fn replace_accounts(&self, accounts: &[Account]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM accounts", [])?;
+        for account in accounts {
+            tx.execute(
+                "INSERT INTO accounts (name, pwd_hash) VALUES (?1, ?2)",
+                params![account.name, account.pwd_hash],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn push_offline(&self, msg: &StoredMessage) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO offline (sender, recipient, content, sent_at) VALUES (?1, ?2, ?3, ?4)",
+            params![msg.from, msg.to, msg.content, msg.sent_at as i64],
+        )?;
+        Ok(())
+    }
+
+    fn take_offline(&self, recipient: &str) -> Result<Vec<StoredMessage>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let messages = {
+            let mut stmt = tx.prepare(
+                "SELECT sender, recipient, content, sent_at FROM offline
+                 WHERE recipient = ?1 ORDER BY id",
+            )?;
+            let rows = stmt.query_map(params![recipient], row_to_message)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        tx.execute(
+            "DELETE FROM offline WHERE recipient = ?1",
+            params![recipient],
+        )?;
+        tx.commit()?;
+        Ok(messages)
+    }
+
+    fn count_offline(&self, recipient: &str) -> Result<usize> {
+        let count: i64 = self.conn().query_row(
+            "SELECT COUNT(*) FROM offline WHERE recipient = ?1",
+            params![recipient],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn expire_offline(&self, recipient: &str, before: u64) -> Result<usize> {
+        let expired = self.conn().execute(
+            "DELETE FROM offline WHERE recipient = ?1 AND sent_at < ?2",
+            params![recipient, before as i64],
+        )?;
+        Ok(expired)
+    }
+
+    fn append_history(&self, msg: &StoredMessage) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO history (sender, recipient, content, sent_at) VALUES (?1, ?2, ?3, ?4)",
+            params![msg.from, msg.to, msg.content, msg.sent_at as i64],
+        )?;
+        Ok(())
+    }
+
+    fn history(&self, to: &str, since: u64, limit: usize) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT sender, recipient, content, sent_at FROM history
+             WHERE recipient = ?1 AND sent_at > ?2 ORDER BY id DESC LIMIT ?3",
+        )?;
+        let mut messages = stmt
+            .query_map(params![to, since as i64, limit as i64], row_to_message)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn load_rooms(&self) -> Result<Vec<RoomRecord>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT name, topic, creator, created_at, members FROM rooms")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut rooms = Vec::new();
+        for (name, topic, creator, created_at, members) in rows {
+            rooms.push(RoomRecord {
+                name,
+                topic,
+                creator,
+                created_at: created_at as u64,
+                members: serde_json::from_str(&members)?,
+            });
+        }
+        Ok(rooms)
+    }
+
+    fn save_room(&self, room: &RoomRecord) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO rooms (name, topic, creator, created_at, members)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                room.name,
+                room.topic,
+                room.creator,
+                room.created_at as i64,
+                serde_json::to_string(&room.members)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_blocks(&self) -> Result<Vec<BlockRecord>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT user, blocked FROM blocks")?;
+        let blocks = stmt
+            .query_map([], |row| {
+                Ok(BlockRecord {
+                    user: row.get(0)?,
+                    blocked: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(blocks)
+    }
+
+    fn add_block(&self, block: &BlockRecord) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO blocks (user, blocked) VALUES (?1, ?2)",
+            params![block.user, block.blocked],
+        )?;
+        Ok(())
+    }
+
+    fn remove_block(&self, block: &BlockRecord) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM blocks WHERE user = ?1 AND blocked = ?2",
+            params![block.user, block.blocked],
+        )?;
+        Ok(())
+    }
+
+    fn load_sessions(&self) -> Result<Vec<SessionRecord>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT id, user, token_hash, created_at, expires_at FROM sessions")?;
+        let sessions = stmt
+            .query_map([], |row| {
+                Ok(SessionRecord {
+                    id: row.get(0)?,
+                    user: row.get(1)?,
+                    token_hash: row.get(2)?,
+                    created_at: row.get::<_, i64>(3)? as u64,
+                    expires_at: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(sessions)
+    }
+
+    fn add_session(&self, session: &SessionRecord) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO sessions (id, user, token_hash, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                session.id,
+                session.user,
+                session.token_hash,
+                session.created_at as i64,
+                session.expires_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_session(&self, id: &str) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
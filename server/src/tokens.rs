@@ -0,0 +1,162 @@
+// Session tokens, so a client can log in again without sending the password.
+//
+// Every password login starts a session with a random token, handed to the
+// client once. Only the SHA-256 digest of the token is stored: it is random
+// and long, so a slow hash like the password one adds nothing, and a leaked
+// store still does not let anyone in. A session ends when it expires or when
+// its owner revokes it. Shared by the connection tasks like `UserStore`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_std::sync::RwLock;
+use async_std::task;
+use sha2::{Digest, Sha256};
+
+use crate::storage::{self, SessionRecord, Storage};
+use crate::Result;
+
+pub struct Tokens {
+    storage: Arc<dyn Storage>,
+    ttl: u64, //seconds a session lasts
+    sessions: RwLock<HashMap<String, SessionRecord>>, //token digest -> session
+}
+
+impl Tokens {
+    //Reads the sessions from the backend, dropping the expired ones
+    pub fn load(storage: Arc<dyn Storage>, ttl: u64) -> Result<Tokens> {
+        let now = storage::now();
+        let mut sessions = HashMap::new();
+        for session in storage.load_sessions()? {
+            if session.expires_at > now {
+                sessions.insert(session.token_hash.clone(), session);
+            } else {
+                storage.remove_session(&session.id)?;
+            }
+        }
+        Ok(Tokens {
+            storage,
+            ttl,
+            sessions: RwLock::new(sessions),
+        })
+    }
+
+    //Starts a session for a user who just logged in, returns the token with it
+    pub async fn issue(&self, user: &str) -> Result<(String, SessionRecord)> {
+        let token = random_hex(32);
+        let mut sessions = self.sessions.write().await;
+        let mut id = random_hex(4);
+        while sessions.values().any(|session| session.id == id) {
+            id = random_hex(4);
+        }
+        let now = storage::now();
+        let session = SessionRecord {
+            id,
+            user: user.to_string(),
+            token_hash: digest(&token),
+            created_at: now,
+            expires_at: now + self.ttl,
+        };
+        let storage = Arc::clone(&self.storage);
+        let record = session.clone();
+        task::spawn_blocking(move || storage.add_session(&record)).await?;
+        sessions.insert(session.token_hash.clone(), session.clone());
+        Ok((token, session))
+    }
+
+    //The session a token belongs to, None if there is none or it expired
+    pub async fn authenticate(&self, token: &str) -> Option<SessionRecord> {
+        let session = self.sessions.read().await.get(&digest(token)).cloned()?;
+        if session.expires_at > storage::now() {
+            return Some(session);
+        }
+        if let Err(e) = self.revoke(&session.user, &session.id).await {
+            log::error!("Failed to drop expired session {}: {}", session.id, e);
+        }
+        None
+    }
+
+    //Sessions of a user that have not expired, oldest first
+    pub async fn list(&self, user: &str) -> Vec<SessionRecord> {
+        let now = storage::now();
+        let mut sessions: Vec<SessionRecord> = self
+            .sessions
+            .read()
+            .await
+            .values()
+            .filter(|session| session.user == user && session.expires_at > now)
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        sessions
+    }
+
+    //Ends a session of `user`, false if they have none with that id
+    pub async fn revoke(&self, user: &str, id: &str) -> Result<bool> {
+        let mut sessions = self.sessions.write().await;
+        let token_hash = match sessions
+            .values()
+            .find(|session| session.user == user && session.id == id)
+        {
+            Some(session) => session.token_hash.clone(),
+            None => return Ok(false),
+        };
+        let storage = Arc::clone(&self.storage);
+        let id = id.to_string();
+        task::spawn_blocking(move || storage.remove_session(&id)).await?;
+        sessions.remove(&token_hash);
+        Ok(true)
+    }
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+fn digest(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FlatFileStorage;
+
+    #[test]
+    fn tokens_log_in_until_revoked_or_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(FlatFileStorage::open(dir.path()).unwrap());
+        let tokens = Tokens::load(Arc::clone(&storage), 60).unwrap();
+        task::block_on(async {
+            let (token, session) = tokens.issue("alice").await.unwrap();
+            let (other, _) = tokens.issue("alice").await.unwrap();
+            assert!(!storage.load_sessions().unwrap()[0].token_hash.contains(&token));
+            assert_eq!(tokens.authenticate(&token).await, Some(session.clone()));
+            assert_eq!(tokens.authenticate("guess").await, None);
+            assert_eq!(tokens.list("alice").await.len(), 2);
+
+            assert!(!tokens.revoke("bob", &session.id).await.unwrap());
+            assert!(tokens.revoke("alice", &session.id).await.unwrap());
+            assert_eq!(tokens.authenticate(&token).await, None);
+
+            //a restart keeps the rest
+            let tokens = Tokens::load(Arc::clone(&storage), 60).unwrap();
+            assert!(tokens.authenticate(&other).await.is_some());
+        });
+
+        //sessions that ran out are gone after a restart
+        let mut expired = storage.load_sessions().unwrap().remove(0);
+        storage.remove_session(&expired.id).unwrap();
+        expired.expires_at = 1;
+        storage.add_session(&expired).unwrap();
+        Tokens::load(Arc::clone(&storage), 60).unwrap();
+        assert!(storage.load_sessions().unwrap().is_empty());
+    }
+}
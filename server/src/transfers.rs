@@ -0,0 +1,632 @@
+// File transfers in flight.
+//
+// Owned by the broker. Only the bookkeeping lives here: who gets the chunks and
+// how many bytes each recipient has. The chunks themselves are forwarded as they
+// arrive. Checking the SHA-256 digest is left to the recipients, who have the
+// whole file.
+//
+// A file is only offered at first. The sender is asked for chunks once a
+// recipient accepts, from the smallest offset any accepting recipient still
+// needs. A returning recipient says how much it has the same way, and chunks a
+// recipient has already are not forwarded to it again. Transfers without any
+// activity for `window` are dropped.
+//
+// The sender may only get `WINDOW` bytes ahead of the slowest online recipient.
+// Recipients ack what they wrote and the sender is granted more as they do, so a
+// stalled recipient holds at most a window of chunks in its queue on the server.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use chat_common::protocol::{ErrorKind, ServerEvent, CHUNK_SIZE};
+
+//How far the sender may get ahead of what the recipients acked
+pub const WINDOW: u64 = 8 * CHUNK_SIZE as u64;
+
+struct Transfer {
+    filename: String,
+    sha256: String,
+    recipients: HashMap<String, Option<u64>>, //bytes each recipient has, None until it accepts
+    acked: HashMap<String, u64>, //bytes each accepting recipient says it wrote
+    size: u64,
+    sent: u64, //offset of the next chunk expected from the sender, `size` while it is idle
+    granted: u64, //the sender may send up to here
+    touched: Instant,
+}
+
+impl Transfer {
+    fn complete(&self) -> bool {
+        self.recipients
+            .values()
+            .all(|&received| received == Some(self.size))
+    }
+
+    fn needed_from(&self) -> u64 {
+        self.recipients
+            .values()
+            .flatten()
+            .copied()
+            .min()
+            .unwrap_or(self.size)
+    }
+
+    //Where the window ends now, None if no accepting recipient is online
+    fn window_end(&self, online: impl Fn(&str) -> bool) -> Option<u64> {
+        self.recipients
+            .iter()
+            .filter(|(name, received)| received.is_some() && online(name))
+            .map(|(name, _)| self.acked.get(name).copied().unwrap_or(0))
+            .min()
+            .map(|acked| (acked + WINDOW).min(self.size))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransferError {
+    InvalidId,
+    SizeChanged,
+    NoSuchTransfer,
+    OutOfOrder { expected: u64 },
+    TooBig,
+    NotGranted { granted: u64 },
+}
+
+impl TransferError {
+    pub fn to_event(&self, transfer: &str) -> ServerEvent {
+        match self {
+            TransferError::InvalidId => ServerEvent::error(
+                ErrorKind::Malformed,
+                "Transfer ids are 1 to 64 letters, digits, - or _",
+            ),
+            TransferError::SizeChanged => ServerEvent::error(
+                ErrorKind::Unexpected,
+                format!("Transfer {} was started with another size", transfer),
+            ),
+            TransferError::NoSuchTransfer => ServerEvent::error(
+                ErrorKind::NoSuchTransfer,
+                format!("There is no transfer {}", transfer),
+            ),
+            TransferError::OutOfOrder { expected } => ServerEvent::error(
+                ErrorKind::InvalidChunk,
+                format!(
+                    "Transfer {} expects the chunk at offset {}",
+                    transfer, expected
+                ),
+            ),
+            TransferError::TooBig => ServerEvent::error(
+                ErrorKind::InvalidChunk,
+                format!(
+                    "Chunk for transfer {} is over {} bytes or past the end of the file",
+                    transfer, CHUNK_SIZE
+                ),
+            ),
+            TransferError::NotGranted { granted } => ServerEvent::error(
+                ErrorKind::InvalidChunk,
+                format!(
+                    "Transfer {} may only send up to byte {} until the recipients catch up",
+                    transfer, granted
+                ),
+            ),
+        }
+    }
+}
+
+//Ids end up in file names on the clients, so keep them boring
+fn valid_id(transfer: &str) -> bool {
+    !transfer.is_empty()
+        && transfer.len() <= 64
+        && transfer
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+//What the recipients are told about a file
+pub struct Offer {
+    pub filename: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Started {
+    New,
+    //none of the recipients can take the file, nothing is kept
+    Nobody,
+    //the sender came back, it should continue at `offset`
+    Resumed { offset: u64 },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resumed {
+    //the sender has to go back to `offset` for this recipient
+    Rewind { offset: u64 },
+    //the sender is behind this recipient anyway, nothing to do
+    Wait,
+    //the recipient had everything, and so has everyone else now
+    Complete,
+}
+
+//A transfer dropped by `expire`
+pub struct Expired {
+    pub from: String,
+    pub transfer: String,
+    pub recipients: Vec<String>,
+}
+
+pub struct Transfers {
+    active: HashMap<(String, String), Transfer>, //(sender, transfer id)
+    window: Duration,
+}
+
+impl Transfers {
+    pub fn new(window: Duration) -> Self {
+        Transfers {
+            active: HashMap::new(),
+            window,
+        }
+    }
+
+    //Registers a transfer or picks up one the sender started before
+    pub fn start(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        recipients: Vec<String>,
+        offer: Offer,
+    ) -> Result<Started, TransferError> {
+        if !valid_id(transfer) {
+            return Err(TransferError::InvalidId);
+        }
+        let key = (from.to_string(), transfer.to_string());
+        if let Some(state) = self.active.get_mut(&key) {
+            if state.size != offer.size {
+                return Err(TransferError::SizeChanged);
+            }
+            state.touched = Instant::now();
+            state.sent = state.needed_from();
+            state.granted = state.sent;
+            return Ok(Started::Resumed { offset: state.sent });
+        }
+        if recipients.is_empty() {
+            return Ok(Started::Nobody);
+        }
+        let transfer = Transfer {
+            filename: offer.filename,
+            sha256: offer.sha256,
+            recipients: recipients.into_iter().map(|name| (name, None)).collect(),
+            acked: HashMap::new(),
+            size: offer.size,
+            sent: offer.size,
+            granted: offer.size,
+            touched: Instant::now(),
+        };
+        self.active.insert(key, transfer);
+        Ok(Started::New)
+    }
+
+    //Accounts for a chunk and returns the recipients that need it and whether
+    //everyone has the whole file now. A complete transfer is forgotten.
+    pub fn chunk(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        offset: u64,
+        len: usize,
+        online: impl Fn(&str) -> bool,
+    ) -> Result<(Vec<String>, bool), TransferError> {
+        let key = (from.to_string(), transfer.to_string());
+        let state = self
+            .active
+            .get_mut(&key)
+            .ok_or(TransferError::NoSuchTransfer)?;
+        if offset != state.sent {
+            return Err(TransferError::OutOfOrder {
+                expected: state.sent,
+            });
+        }
+        if len == 0 || len > CHUNK_SIZE || state.sent + len as u64 > state.size {
+            return Err(TransferError::TooBig);
+        }
+        if state.sent + len as u64 > state.granted {
+            return Err(TransferError::NotGranted {
+                granted: state.granted,
+            });
+        }
+        state.sent += len as u64;
+        state.touched = Instant::now();
+
+        //a recipient gets the chunk if it continues its file, the part it
+        //has already is skipped on its side
+        let end = offset + len as u64;
+        let mut forward = Vec::new();
+        for (name, received) in state.recipients.iter_mut() {
+            if let Some(received) = received {
+                if offset <= *received && *received < end && online(name) {
+                    *received = end;
+                    forward.push(name.clone());
+                }
+            }
+        }
+        let complete = state.complete();
+        if complete {
+            self.active.remove(&key);
+        }
+        Ok((forward, complete))
+    }
+
+    //A recipient accepts the file or, after a reconnect, reports how much of
+    //it it has
+    pub fn resume(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        recipient: &str,
+        offset: u64,
+    ) -> Result<Resumed, TransferError> {
+        let key = (from.to_string(), transfer.to_string());
+        let state = self
+            .active
+            .get_mut(&key)
+            .ok_or(TransferError::NoSuchTransfer)?;
+        let size = state.size;
+        let received = state
+            .recipients
+            .get_mut(recipient)
+            .ok_or(TransferError::NoSuchTransfer)?;
+        if offset > size {
+            return Err(TransferError::TooBig);
+        }
+        *received = Some(offset);
+        state.acked.insert(recipient.to_string(), offset);
+        state.touched = Instant::now();
+
+        if state.complete() {
+            self.active.remove(&key);
+            return Ok(Resumed::Complete);
+        }
+        if offset < state.sent {
+            state.sent = offset;
+            state.granted = offset;
+            return Ok(Resumed::Rewind { offset });
+        }
+        Ok(Resumed::Wait)
+    }
+
+    //A recipient wrote the file up to `offset`
+    pub fn ack(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        recipient: &str,
+        offset: u64,
+    ) -> Result<(), TransferError> {
+        let key = (from.to_string(), transfer.to_string());
+        let state = self
+            .active
+            .get_mut(&key)
+            .ok_or(TransferError::NoSuchTransfer)?;
+        let received = state
+            .recipients
+            .get(recipient)
+            .copied()
+            .flatten()
+            .ok_or(TransferError::NoSuchTransfer)?;
+        //nobody can have written what was not forwarded yet
+        let acked = state.acked.entry(recipient.to_string()).or_default();
+        *acked = (*acked).max(offset.min(received));
+        state.touched = Instant::now();
+        Ok(())
+    }
+
+    //Moves the window of a transfer along with the online recipients and
+    //returns where it ends if the sender may send more now
+    pub fn grant(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        online: impl Fn(&str) -> bool,
+    ) -> Option<u64> {
+        let key = (from.to_string(), transfer.to_string());
+        let state = self.active.get_mut(&key)?;
+        let end = state.window_end(online)?;
+        if end <= state.granted {
+            return None;
+        }
+        state.granted = end;
+        Some(end)
+    }
+
+    //`grant` for every transfer, for when a recipient went offline and no
+    //longer holds the others back. Returns (sender, transfer, window end).
+    pub fn grant_all(&mut self, online: impl Fn(&str) -> bool) -> Vec<(String, String, u64)> {
+        let mut granted = Vec::new();
+        for ((from, transfer), state) in self.active.iter_mut() {
+            if let Some(end) = state.window_end(&online) {
+                if end > state.granted {
+                    state.granted = end;
+                    granted.push((from.clone(), transfer.clone(), end));
+                }
+            }
+        }
+        granted
+    }
+
+    //A recipient turns the file down. Returns whether that ended the transfer,
+    //and if so whether anyone got the whole file.
+    pub fn reject(
+        &mut self,
+        from: &str,
+        transfer: &str,
+        recipient: &str,
+    ) -> Result<Option<bool>, TransferError> {
+        let key = (from.to_string(), transfer.to_string());
+        let state = self
+            .active
+            .get_mut(&key)
+            .ok_or(TransferError::NoSuchTransfer)?;
+        state
+            .recipients
+            .remove(recipient)
+            .ok_or(TransferError::NoSuchTransfer)?;
+        state.acked.remove(recipient);
+        state.touched = Instant::now();
+
+        if state.recipients.is_empty() {
+            self.active.remove(&key);
+            return Ok(Some(false));
+        }
+        if state.complete() {
+            self.active.remove(&key);
+            return Ok(Some(true));
+        }
+        Ok(None)
+    }
+
+    //Offers `recipient` has not answered yet, sent again when it logs in
+    pub fn pending(&self, recipient: &str) -> Vec<ServerEvent> {
+        self.active
+            .iter()
+            .filter(|(_, state)| state.recipients.get(recipient) == Some(&None))
+            .map(|((from, transfer), state)| ServerEvent::FileOffer {
+                from: from.clone(),
+                transfer: transfer.clone(),
+                filename: state.filename.clone(),
+                size: state.size,
+                sha256: state.sha256.clone(),
+            })
+            .collect()
+    }
+
+    //Drops the transfers nobody touched within the window
+    pub fn expire(&mut self) -> Vec<Expired> {
+        let window = self.window;
+        let keys: Vec<(String, String)> = self
+            .active
+            .iter()
+            .filter(|(_, state)| state.touched.elapsed() > window)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| {
+                let state = self.active.remove(&key)?;
+                Some(Expired {
+                    from: key.0,
+                    transfer: key.1,
+                    recipients: state.recipients.into_keys().collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn offer(size: u64) -> Offer {
+        Offer {
+            filename: "notes.txt".to_string(),
+            size,
+            sha256: "00".to_string(),
+        }
+    }
+
+    #[test]
+    fn chunks_in_order_until_complete() {
+        let mut transfers = Transfers::new(Duration::from_secs(60));
+        assert_eq!(
+            transfers.start("alice", "a/b", names(&["bob"]), offer(10)),
+            Err(TransferError::InvalidId)
+        );
+        assert_eq!(
+            transfers.start("alice", "t1", names(&["bob"]), offer(10)),
+            Ok(Started::New)
+        );
+        //nothing is sent before bob accepts
+        assert_eq!(
+            transfers.chunk("alice", "t1", 0, 4, |_| true),
+            Err(TransferError::OutOfOrder { expected: 10 })
+        );
+        assert_eq!(
+            transfers.resume("alice", "t1", "bob", 0),
+            Ok(Resumed::Rewind { offset: 0 })
+        );
+        assert_eq!(transfers.grant("alice", "t1", |_| true), Some(10));
+
+        assert_eq!(
+            transfers.chunk("alice", "t1", 0, 4, |_| true),
+            Ok((names(&["bob"]), false))
+        );
+        assert_eq!(
+            transfers.chunk("alice", "t1", 0, 4, |_| true),
+            Err(TransferError::OutOfOrder { expected: 4 })
+        );
+        assert_eq!(
+            transfers.chunk("alice", "t1", 4, 7, |_| true),
+            Err(TransferError::TooBig)
+        );
+        assert_eq!(
+            transfers.chunk("alice", "t1", 4, 6, |_| true),
+            Ok((names(&["bob"]), true))
+        );
+        assert_eq!(
+            transfers.chunk("alice", "t1", 10, 1, |_| true),
+            Err(TransferError::NoSuchTransfer)
+        );
+    }
+
+    #[test]
+    fn resumes_after_recipient_and_sender_return() {
+        let mut transfers = Transfers::new(Duration::from_secs(60));
+        transfers
+            .start("alice", "t1", names(&["bob", "carol"]), offer(12))
+            .unwrap();
+        transfers.resume("alice", "t1", "bob", 0).unwrap();
+        assert_eq!(
+            transfers.resume("alice", "t1", "carol", 0),
+            Ok(Resumed::Wait)
+        );
+        transfers.grant("alice", "t1", |_| true).unwrap();
+        transfers.chunk("alice", "t1", 0, 4, |_| true).unwrap();
+
+        //carol is gone for the second chunk
+        assert_eq!(
+            transfers.chunk("alice", "t1", 4, 4, |name| name != "carol"),
+            Ok((names(&["bob"]), false))
+        );
+        //back with the first chunk only, the sender has to go back for her
+        assert_eq!(
+            transfers.resume("alice", "t1", "carol", 4),
+            Ok(Resumed::Rewind { offset: 4 })
+        );
+        transfers.grant("alice", "t1", |_| true).unwrap();
+        //bob has that chunk already and does not get it twice
+        assert_eq!(
+            transfers.chunk("alice", "t1", 4, 4, |_| true),
+            Ok((names(&["carol"]), false))
+        );
+
+        //a recipient that stopped in the middle of a chunk gets the whole chunk
+        assert_eq!(
+            transfers.resume("alice", "t1", "carol", 4),
+            Ok(Resumed::Rewind { offset: 4 })
+        );
+        assert_eq!(transfers.resume("alice", "t1", "bob", 6), Ok(Resumed::Wait));
+        transfers.grant("alice", "t1", |_| true).unwrap();
+        let (mut forward, _) = transfers.chunk("alice", "t1", 4, 4, |_| true).unwrap();
+        forward.sort();
+        assert_eq!(forward, names(&["bob", "carol"]));
+
+        //the sender reconnects and starts over, it continues where both are
+        assert_eq!(
+            transfers.start("alice", "t1", names(&["bob", "carol"]), offer(12)),
+            Ok(Started::Resumed { offset: 8 })
+        );
+        assert_eq!(transfers.grant("alice", "t1", |_| true), Some(12));
+        assert_eq!(
+            transfers.start("alice", "t1", names(&["bob"]), offer(13)),
+            Err(TransferError::SizeChanged)
+        );
+        assert_eq!(
+            transfers.resume("alice", "t1", "dave", 0),
+            Err(TransferError::NoSuchTransfer)
+        );
+        let (mut forward, complete) = transfers.chunk("alice", "t1", 8, 4, |_| true).unwrap();
+        forward.sort();
+        assert_eq!((forward, complete), (names(&["bob", "carol"]), true));
+    }
+
+    #[test]
+    fn rejected_offers_end_the_transfer() {
+        let mut transfers = Transfers::new(Duration::from_secs(60));
+        transfers
+            .start("alice", "t1", names(&["bob", "carol"]), offer(4))
+            .unwrap();
+        assert_eq!(transfers.pending("bob").len(), 1);
+        transfers.resume("alice", "t1", "bob", 0).unwrap();
+        transfers.grant("alice", "t1", |_| true).unwrap();
+        assert!(transfers.pending("bob").is_empty());
+        transfers.chunk("alice", "t1", 0, 4, |_| true).unwrap();
+
+        //carol's answer is the last one missing
+        assert_eq!(transfers.reject("alice", "t1", "carol"), Ok(Some(true)));
+        assert!(transfers.pending("carol").is_empty());
+
+        transfers
+            .start("alice", "t2", names(&["bob"]), offer(4))
+            .unwrap();
+        assert_eq!(transfers.reject("alice", "t2", "bob"), Ok(Some(false)));
+        assert_eq!(
+            transfers.reject("alice", "t2", "bob"),
+            Err(TransferError::NoSuchTransfer)
+        );
+        assert_eq!(
+            transfers.start("alice", "t3", Vec::new(), offer(4)),
+            Ok(Started::Nobody)
+        );
+    }
+
+    #[test]
+    fn sender_stays_within_the_window() {
+        let chunk = CHUNK_SIZE as u64;
+        let mut transfers = Transfers::new(Duration::from_secs(60));
+        transfers
+            .start("alice", "t1", names(&["bob", "carol"]), offer(20 * chunk))
+            .unwrap();
+        transfers.resume("alice", "t1", "bob", 0).unwrap();
+        transfers.resume("alice", "t1", "carol", 0).unwrap();
+        assert_eq!(transfers.grant("alice", "t1", |_| true), Some(WINDOW));
+        let mut offset = 0;
+        while offset < WINDOW {
+            transfers
+                .chunk("alice", "t1", offset, CHUNK_SIZE, |_| true)
+                .unwrap();
+            offset += chunk;
+        }
+        assert_eq!(
+            transfers.chunk("alice", "t1", offset, CHUNK_SIZE, |_| true),
+            Err(TransferError::NotGranted { granted: WINDOW })
+        );
+
+        //bob catching up is not enough while carol is stalled
+        transfers.ack("alice", "t1", "bob", WINDOW).unwrap();
+        assert_eq!(transfers.grant("alice", "t1", |_| true), None);
+        transfers.ack("alice", "t1", "carol", chunk).unwrap();
+        assert_eq!(transfers.grant("alice", "t1", |_| true), Some(WINDOW + chunk));
+        //an ack can't run ahead of what was forwarded
+        transfers.ack("alice", "t1", "carol", 20 * chunk).unwrap();
+        assert_eq!(transfers.grant("alice", "t1", |_| true), Some(2 * WINDOW));
+
+        //carol lost a part and the sender goes back for her
+        assert_eq!(
+            transfers.resume("alice", "t1", "carol", chunk),
+            Ok(Resumed::Rewind { offset: chunk })
+        );
+        assert_eq!(transfers.grant("alice", "t1", |_| true), Some(WINDOW + chunk));
+        //then she goes offline, only bob holds the sender back now
+        assert_eq!(
+            transfers.grant_all(|name| name != "carol"),
+            vec![("alice".to_string(), "t1".to_string(), 2 * WINDOW)]
+        );
+    }
+
+    #[test]
+    fn idle_transfers_expire() {
+        let mut transfers = Transfers::new(Duration::ZERO);
+        transfers
+            .start("alice", "t1", names(&["bob"]), offer(10))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let expired = transfers.expire();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].recipients, names(&["bob"]));
+        assert_eq!(
+            transfers.resume("alice", "t1", "bob", 0),
+            Err(TransferError::NoSuchTransfer)
+        );
+    }
+}
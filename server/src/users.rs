@@ -0,0 +1,152 @@
+// In-memory index of the user accounts.
+//
+// Accounts are read from the storage backend once at startup, after that every
+// lookup is a hash map access. New accounts are written through to the backend,
+// whole-store changes go through `Storage::replace_accounts`. The lock is an async
+// one so waiting tasks yield instead of blocking an executor thread.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use async_std::sync::RwLock;
+use async_std::task;
+
+use crate::password;
+use crate::storage::{Account, Storage};
+use crate::Result;
+
+pub struct UserStore {
+    storage: Arc<dyn Storage>,
+    users: RwLock<HashMap<String, String>>, //name -> password hash
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    Taken,
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::Taken => write!(f, "username taken"),
+            RegisterError::Storage(e) => write!(f, "could not save user: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+impl UserStore {
+    //Reads every account from the backend
+    pub fn load(storage: Arc<dyn Storage>) -> Result<UserStore> {
+        let users = storage
+            .load_accounts()?
+            .into_iter()
+            .map(|account| (account.name, account.pwd_hash))
+            .collect();
+        Ok(UserStore {
+            storage,
+            users: RwLock::new(users),
+        })
+    }
+
+    pub async fn len(&self) -> usize {
+        self.users.read().await.len()
+    }
+
+    pub async fn contains(&self, name: &str) -> bool {
+        self.users.read().await.contains_key(name)
+    }
+
+    //Stored password hash of a user, None if the user does not exist
+    pub async fn password_hash(&self, name: &str) -> Option<String> {
+        self.users.read().await.get(name).cloned()
+    }
+
+    //Adds a user and persists it.
+    //Check and insert happen under one write lock, so of two clients racing for
+    //the same name exactly one succeeds and the other gets `RegisterError::Taken`.
+    pub async fn register(
+        &self,
+        name: &str,
+        pwd_hash: &str,
+    ) -> std::result::Result<(), RegisterError> {
+        let mut users = self.users.write().await;
+        if users.contains_key(name) {
+            return Err(RegisterError::Taken);
+        }
+        let account = Account {
+            name: name.to_string(),
+            pwd_hash: pwd_hash.to_string(),
+        };
+        let storage = Arc::clone(&self.storage);
+        task::spawn_blocking(move || storage.insert_account(&account))
+            .await
+            .map_err(RegisterError::Storage)?;
+        users.insert(name.to_string(), pwd_hash.to_string());
+        Ok(())
+    }
+
+    //Hashes every legacy plaintext password and rewrites the stored accounts.
+    //A one-shot upgrade, once the store only holds hashes this does nothing.
+    pub async fn migrate_plaintext(&self) -> Result<usize> {
+        let mut users = self.users.write().await;
+        let plaintext: Vec<(String, String)> = users
+            .iter()
+            .filter(|(_, pwd)| !password::is_hashed(pwd))
+            .map(|(name, pwd)| (name.clone(), pwd.clone()))
+            .collect();
+        if plaintext.is_empty() {
+            return Ok(0);
+        }
+
+        let hashed = task::spawn_blocking(move || {
+            plaintext
+                .into_iter()
+                .map(|(name, pwd)| Ok((name, password::hash(&pwd)?)))
+                .collect::<Result<Vec<_>>>()
+        })
+        .await?;
+        let migrated = hashed.len();
+        users.extend(hashed);
+
+        let mut accounts: Vec<Account> = users
+            .iter()
+            .map(|(name, pwd_hash)| Account {
+                name: name.clone(),
+                pwd_hash: pwd_hash.clone(),
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        let storage = Arc::clone(&self.storage);
+        task::spawn_blocking(move || storage.replace_accounts(&accounts)).await?;
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FlatFileStorage;
+
+    #[test]
+    fn concurrent_registration_has_one_winner() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(FlatFileStorage::open(dir.path()).unwrap());
+        let store = Arc::new(UserStore::load(storage.clone()).unwrap());
+
+        let results = task::block_on(futures::future::join_all((0..8).map(|i| {
+            let store = Arc::clone(&store);
+            task::spawn(async move { store.register("alice", &format!("hash{}", i)).await })
+        })));
+        let winners = results.iter().filter(|r| r.is_ok()).count();
+        let taken = results
+            .iter()
+            .filter(|r| matches!(r, Err(RegisterError::Taken)))
+            .count();
+        assert_eq!((winners, taken), (1, 7));
+        assert_eq!(storage.load_accounts().unwrap().len(), 1);
+    }
+}